@@ -2,7 +2,7 @@ use std::io::Read;
 
 use crate::version::Version;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum CompressionType {
     None,
     LZ4,
@@ -33,6 +33,17 @@ const XOR_KEY_BETA: &[u8] = &[
     0xA0, 0x31, 0x34, 0x79, 0xF8, 0x52, 0xF2, 0xD1, 0xED, 0xC8, 0x62, 0x86, 0x12, 0xF0, 0x4B, 0x97,
 ];
 
+/// XORs the start of a G108 payload, the same operation both ways.
+fn xor_g108(version: &Version, buf: &mut [u8]) {
+    let xor_size = (buf.len() - 8).clamp(0, 256);
+    for (i, x) in buf[8..8 + xor_size].iter_mut().enumerate() {
+        match *version {
+            Version::ClosedAlpha => *x ^= 0x5E,
+            Version::ClosedBeta => *x = !(*x ^ XOR_KEY_BETA[i % XOR_KEY_BETA.len()]),
+        }
+    }
+}
+
 pub fn decompress(
     version: &Version,
     compression_type: CompressionType,
@@ -40,22 +51,14 @@ pub fn decompress(
 ) -> Result<Vec<u8>, anyhow::Error> {
     let mut buf = buf.to_vec();
     match compression_type {
-        CompressionType::G108Lz4 | CompressionType::G108Zstd => {
-            let xor_size = (buf.len() - 8).clamp(0, 256);
-            for (i, x) in buf[8..8 + xor_size].iter_mut().enumerate() {
-                match *version {
-                    Version::ClosedAlpha => *x ^= 0x5E,
-                    Version::ClosedBeta => *x = !(*x ^ XOR_KEY_BETA[i % XOR_KEY_BETA.len()]),
-                }
-            }
-        }
+        CompressionType::G108Lz4 | CompressionType::G108Zstd => xor_g108(version, &mut buf),
         _ => {}
     }
     let decsize = u32::from_le_bytes(buf[4..8].try_into().unwrap());
     let mut decompressed = Vec::new();
     match compression_type {
         CompressionType::None => {
-            decompressed = buf[0..].to_vec();
+            decompressed = buf[8..].to_vec();
         }
         CompressionType::Zlib => {
             let buf = unxor_zlib(&mut buf);
@@ -88,6 +91,40 @@ pub fn decompress(
     Ok(decompressed)
 }
 
+/// Wraps `buf` in a compression container, for the formats we have an encoder for.
+pub fn compress(
+    version: &Version,
+    compression_type: CompressionType,
+    buf: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+    let magic = match compression_type {
+        CompressionType::None => b"NNNN",
+        CompressionType::LZ4 => b"ZZZ4",
+        CompressionType::Zstd => b"ZSTD",
+        CompressionType::G108Lz4 => b"1084",
+        CompressionType::G108Zstd => b"108D",
+        _ => anyhow::bail!("can't write {:?} compressed data", compression_type),
+    };
+    let mut compressed = magic.to_vec();
+    compressed.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+    match compression_type {
+        CompressionType::LZ4 | CompressionType::G108Lz4 => {
+            compressed.extend_from_slice(&lz4_flex::compress(buf));
+        }
+        CompressionType::Zstd | CompressionType::G108Zstd => {
+            compressed.extend_from_slice(&zstd::encode_all(buf, 0)?);
+        }
+        _ => compressed.extend_from_slice(buf),
+    }
+    if matches!(
+        compression_type,
+        CompressionType::G108Lz4 | CompressionType::G108Zstd
+    ) {
+        xor_g108(version, &mut compressed);
+    }
+    Ok(compressed)
+}
+
 fn unxor_zlib(buf: &mut [u8]) -> &[u8] {
     let offset = (buf.len() - 8) % 37;
     let end = 128 - offset;
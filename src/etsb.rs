@@ -0,0 +1,860 @@
+use anyhow::{bail, Context, Error, Result};
+use binrw::{binread, BinReaderExt};
+use rayon::prelude::*;
+use rmpv::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use std::{
+    fs::File,
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    compression::{self, CompressionType},
+    file,
+    version::Version,
+};
+
+/// `|S\xb6\xc8`, the start of the header some ETSB/MONB files carry.
+pub const HEADER_MAGIC: [u8; 4] = [0x7c, 0x53, 0xb6, 0xc8];
+pub const HEADER_SIZE: usize = 8;
+
+// TODO: only the magic is certain, the rest is named after which bytes vary between files
+#[binread]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[br(little, magic = b"|S\xb6\xc8")]
+pub struct EtsbHeader {
+    pub version: u16,
+    pub flags: u16,
+}
+
+impl EtsbHeader {
+    pub fn read(data: &[u8]) -> Option<Self> {
+        Cursor::new(data).read_le().ok()
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(&HEADER_MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..].copy_from_slice(&self.flags.to_le_bytes());
+        bytes
+    }
+}
+
+/// A decoded ETSB/MONB file, with everything needed to write the same bytes back.
+#[derive(Debug, Clone)]
+pub struct Etsb {
+    pub header: Option<EtsbHeader>,
+    /// Container the msgpack body was compressed with, if any
+    pub compression: Option<CompressionType>,
+    pub body: Value,
+    /// Marker of every msgpack value in the body, in the order they are written
+    pub markers: Vec<&'static str>,
+    /// Anything after the msgpack body
+    pub trailing: Vec<u8>,
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() / 2 * 2 != hex.len() {
+        bail!("odd length hex string {:?}", hex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .with_context(|| format!("invalid hex string {:?}", hex))
+        })
+        .collect()
+}
+
+fn read_be(data: &[u8], pos: usize, size: usize) -> Result<usize> {
+    let bytes = data
+        .get(pos..pos + size)
+        .context("msgpack body is truncated")?;
+    Ok(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize))
+}
+
+/// Walks the msgpack value at `pos`, recording the marker of it and every value inside it.
+/// Returns the position after the value.
+fn scan_markers(data: &[u8], pos: usize, markers: &mut Vec<&'static str>) -> Result<usize> {
+    let marker = *data.get(pos).context("msgpack body is truncated")?;
+    let pos = pos + 1;
+    // (name, payload size, child count)
+    let (name, size, children) = match marker {
+        0x00..=0x7f | 0xe0..=0xff => ("fixint", 0, 0),
+        0x80..=0x8f => ("fixmap", 0, (marker & 0xf) as usize * 2),
+        0x90..=0x9f => ("fixarray", 0, (marker & 0xf) as usize),
+        0xa0..=0xbf => ("fixstr", (marker & 0x1f) as usize, 0),
+        0xc0 => ("nil", 0, 0),
+        0xc2 | 0xc3 => ("bool", 0, 0),
+        0xc4 => ("bin8", 1 + read_be(data, pos, 1)?, 0),
+        0xc5 => ("bin16", 2 + read_be(data, pos, 2)?, 0),
+        0xc6 => ("bin32", 4 + read_be(data, pos, 4)?, 0),
+        0xc7 => ("ext8", 2 + read_be(data, pos, 1)?, 0),
+        0xc8 => ("ext16", 3 + read_be(data, pos, 2)?, 0),
+        0xc9 => ("ext32", 5 + read_be(data, pos, 4)?, 0),
+        0xca => ("f32", 4, 0),
+        0xcb => ("f64", 8, 0),
+        0xcc => ("u8", 1, 0),
+        0xcd => ("u16", 2, 0),
+        0xce => ("u32", 4, 0),
+        0xcf => ("u64", 8, 0),
+        0xd0 => ("i8", 1, 0),
+        0xd1 => ("i16", 2, 0),
+        0xd2 => ("i32", 4, 0),
+        0xd3 => ("i64", 8, 0),
+        0xd4..=0xd8 => ("fixext", 1 + (1 << (marker - 0xd4)), 0),
+        0xd9 => ("str8", 1 + read_be(data, pos, 1)?, 0),
+        0xda => ("str16", 2 + read_be(data, pos, 2)?, 0),
+        0xdb => ("str32", 4 + read_be(data, pos, 4)?, 0),
+        0xdc => ("array16", 2, read_be(data, pos, 2)?),
+        0xdd => ("array32", 4, read_be(data, pos, 4)?),
+        0xde => ("map16", 2, read_be(data, pos, 2)? * 2),
+        0xdf => ("map32", 4, read_be(data, pos, 4)? * 2),
+        0xc1 => bail!("reserved msgpack marker 0xc1 at {:#x}", pos - 1),
+    };
+    markers.push(name);
+    let mut pos = pos + size;
+    for _ in 0..children {
+        pos = scan_markers(data, pos, markers)?;
+    }
+    Ok(pos)
+}
+
+/// The marker `rmpv` would pick, i.e. the smallest encoding of `value`.
+fn canonical_marker(value: &Value) -> &'static str {
+    fn sized(
+        len: usize,
+        fix: Option<(&'static str, usize)>,
+        names: [&'static str; 3],
+    ) -> &'static str {
+        match fix {
+            Some((fix, max)) if len <= max => fix,
+            _ if len <= u8::MAX as usize && !names[0].is_empty() => names[0],
+            _ if len <= u16::MAX as usize => names[1],
+            _ => names[2],
+        }
+    }
+    match value {
+        Value::Nil => "nil",
+        Value::Boolean(_) => "bool",
+        Value::Integer(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) if n < 0x80 => "fixint",
+            (Some(n), _) if n <= u8::MAX as u64 => "u8",
+            (Some(n), _) if n <= u16::MAX as u64 => "u16",
+            (Some(n), _) if n <= u32::MAX as u64 => "u32",
+            (Some(_), _) => "u64",
+            (_, Some(n)) if n >= -32 => "fixint",
+            (_, Some(n)) if n >= i8::MIN as i64 => "i8",
+            (_, Some(n)) if n >= i16::MIN as i64 => "i16",
+            (_, Some(n)) if n >= i32::MIN as i64 => "i32",
+            _ => "i64",
+        },
+        Value::F32(_) => "f32",
+        Value::F64(_) => "f64",
+        Value::String(s) => sized(
+            s.as_bytes().len(),
+            Some(("fixstr", 31)),
+            ["str8", "str16", "str32"],
+        ),
+        Value::Binary(b) => sized(b.len(), None, ["bin8", "bin16", "bin32"]),
+        Value::Array(a) => sized(a.len(), Some(("fixarray", 15)), ["", "array16", "array32"]),
+        Value::Map(m) => sized(m.len(), Some(("fixmap", 15)), ["", "map16", "map32"]),
+        Value::Ext(_, data) if matches!(data.len(), 1 | 2 | 4 | 8 | 16) => "fixext",
+        Value::Ext(_, data) => sized(data.len(), None, ["ext8", "ext16", "ext32"]),
+    }
+}
+
+/// Writes a length with the marker named `marker`, e.g. `str16`.
+fn write_len(
+    out: &mut Vec<u8>,
+    marker: &str,
+    len: usize,
+    fix: Option<(u8, usize)>,
+    codes: [Option<u8>; 3],
+) -> Result<()> {
+    let (code, size) = if marker.starts_with("fix") {
+        match fix {
+            Some((base, max)) if len <= max => {
+                out.push(base | len as u8);
+                return Ok(());
+            }
+            _ => bail!("{} entries don't fit in a {}", len, marker),
+        }
+    } else if marker.ends_with("16") {
+        (codes[1], 2)
+    } else if marker.ends_with("32") {
+        (codes[2], 4)
+    } else {
+        (codes[0], 1)
+    };
+    let Some(code) = code else {
+        bail!("invalid marker {:?}", marker);
+    };
+    if size < 4 && len >> (size * 8) != 0 {
+        bail!("{} entries don't fit in a {}", len, marker);
+    }
+    out.push(code);
+    out.extend_from_slice(&(len as u32).to_be_bytes()[4 - size..]);
+    Ok(())
+}
+
+fn write_int(out: &mut Vec<u8>, marker: &str, n: i128) -> Result<()> {
+    let (code, size, min, max) = match marker {
+        "fixint" if (0..0x80).contains(&n) || (-32..0).contains(&n) => {
+            out.push(n as i8 as u8);
+            return Ok(());
+        }
+        "u8" => (0xcc, 1, 0, u8::MAX as i128),
+        "u16" => (0xcd, 2, 0, u16::MAX as i128),
+        "u32" => (0xce, 4, 0, u32::MAX as i128),
+        "u64" => (0xcf, 8, 0, u64::MAX as i128),
+        "i8" => (0xd0, 1, i8::MIN as i128, i8::MAX as i128),
+        "i16" => (0xd1, 2, i16::MIN as i128, i16::MAX as i128),
+        "i32" => (0xd2, 4, i32::MIN as i128, i32::MAX as i128),
+        "i64" => (0xd3, 8, i64::MIN as i128, i64::MAX as i128),
+        _ => bail!("{} can't be written as {:?}", n, marker),
+    };
+    if n < min || n > max {
+        bail!("{} doesn't fit in a {}", n, marker);
+    }
+    out.push(code);
+    out.extend_from_slice(&n.to_be_bytes()[16 - size..]);
+    Ok(())
+}
+
+/// Encodes `value` using the recorded markers, so an unchanged file comes out byte for byte.
+fn write_value<'a>(
+    out: &mut Vec<u8>,
+    value: &Value,
+    markers: &mut impl Iterator<Item = &'a &'static str>,
+) -> Result<()> {
+    let marker = *markers.next().context("ran out of msgpack markers")?;
+    match value {
+        Value::Nil => out.push(0xc0),
+        Value::Boolean(b) => out.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Integer(n) => {
+            let n = n
+                .as_i64()
+                .map(i128::from)
+                .or(n.as_u64().map(i128::from))
+                .unwrap_or_default();
+            write_int(out, marker, n)?;
+        }
+        Value::F32(f) => {
+            out.push(0xca);
+            out.extend_from_slice(&f.to_be_bytes());
+        }
+        Value::F64(f) => {
+            out.push(0xcb);
+            out.extend_from_slice(&f.to_be_bytes());
+        }
+        Value::String(s) => {
+            let bytes = s.as_bytes();
+            write_len(
+                out,
+                marker,
+                bytes.len(),
+                Some((0xa0, 31)),
+                [Some(0xd9), Some(0xda), Some(0xdb)],
+            )?;
+            out.extend_from_slice(bytes);
+        }
+        Value::Binary(bytes) => {
+            write_len(
+                out,
+                marker,
+                bytes.len(),
+                None,
+                [Some(0xc4), Some(0xc5), Some(0xc6)],
+            )?;
+            out.extend_from_slice(bytes);
+        }
+        Value::Array(values) => {
+            write_len(
+                out,
+                marker,
+                values.len(),
+                Some((0x90, 15)),
+                [None, Some(0xdc), Some(0xdd)],
+            )?;
+            for value in values {
+                write_value(out, value, markers)?;
+            }
+        }
+        Value::Map(entries) => {
+            write_len(
+                out,
+                marker,
+                entries.len(),
+                Some((0x80, 15)),
+                [None, Some(0xde), Some(0xdf)],
+            )?;
+            for (key, value) in entries {
+                write_value(out, key, markers)?;
+                write_value(out, value, markers)?;
+            }
+        }
+        Value::Ext(ext_type, data) => {
+            if marker == "fixext" {
+                let code = match data.len() {
+                    1 => 0xd4,
+                    2 => 0xd5,
+                    4 => 0xd6,
+                    8 => 0xd7,
+                    16 => 0xd8,
+                    len => bail!("{} bytes don't fit in a fixext", len),
+                };
+                out.push(code);
+            } else {
+                write_len(
+                    out,
+                    marker,
+                    data.len(),
+                    None,
+                    [Some(0xc7), Some(0xc8), Some(0xc9)],
+                )?;
+            }
+            out.push(*ext_type as u8);
+            out.extend_from_slice(data);
+        }
+    }
+    Ok(())
+}
+
+impl Etsb {
+    pub fn decode(data: &[u8], version: &Version) -> Result<Self> {
+        let header = EtsbHeader::read(data);
+        let body = if header.is_some() {
+            &data[HEADER_SIZE..]
+        } else {
+            data
+        };
+
+        let compression = (body.len() >= 8)
+            .then(|| compression::get_compression_type(body))
+            .flatten();
+        let decompressed;
+        let body = match compression {
+            None => body,
+            Some(compression_type) => {
+                decompressed = compression::decompress(version, compression_type, body)
+                    .with_context(|| format!("decompressing {:?} body", compression_type))?;
+                &decompressed
+            }
+        };
+
+        let mut markers = Vec::new();
+        let end = scan_markers(body, 0, &mut markers)?;
+        let mut reader = Cursor::new(body);
+        let value = rmpv::decode::read_value(&mut reader)?;
+        debug_assert_eq!(reader.position() as usize, end);
+        Ok(Self {
+            header,
+            compression,
+            body: value,
+            markers,
+            trailing: body[end..].to_vec(),
+        })
+    }
+
+    /// Compressed bodies won't come out byte for byte, our encoders don't match the game's.
+    pub fn encode(&self, version: &Version) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut markers = self.markers.iter();
+        write_value(&mut body, &self.body, &mut markers)?;
+        if markers.next().is_some() {
+            bail!("more msgpack markers than values");
+        }
+        body.extend_from_slice(&self.trailing);
+
+        let mut out = Vec::new();
+        if let Some(header) = &self.header {
+            out.extend_from_slice(&header.to_bytes());
+        }
+        match self.compression {
+            Some(compression_type) => {
+                out.extend(compression::compress(version, compression_type, &body)?)
+            }
+            None => out.extend(body),
+        }
+        Ok(out)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut markers = self.markers.iter();
+        let mut json = json!({
+            "header": self.header,
+            "compression": self.compression,
+            "body": value_to_json(&self.body, &mut markers),
+        });
+        if !self.trailing.is_empty() {
+            json["trailing"] = json!(to_hex(&self.trailing));
+        }
+        json
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Result<Self> {
+        let header = match &json["header"] {
+            serde_json::Value::Null => None,
+            header => Some(
+                serde_json::from_value(header.clone())
+                    .with_context(|| format!("invalid header {}", header))?,
+            ),
+        };
+        let compression = match &json["compression"] {
+            serde_json::Value::Null => None,
+            compression => Some(
+                serde_json::from_value(compression.clone())
+                    .with_context(|| format!("unknown compression {}", compression))?,
+            ),
+        };
+        let mut markers = Vec::new();
+        let body = value_from_json(&json["body"], &mut markers).context("in body")?;
+        let trailing = match json.get("trailing") {
+            Some(serde_json::Value::String(hex)) => from_hex(hex)?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            header,
+            compression,
+            body,
+            markers,
+            trailing,
+        })
+    }
+}
+
+fn tagged(tag: &str, value: serde_json::Value) -> serde_json::Value {
+    let mut object = Map::new();
+    object.insert(tag.to_string(), value);
+    serde_json::Value::Object(object)
+}
+
+fn float_to_json(tag: &str, value: f64, bits: String) -> serde_json::Value {
+    match serde_json::Number::from_f64(value) {
+        Some(n) if tag == "$f64" => serde_json::Value::Number(n),
+        Some(n) => tagged(tag, serde_json::Value::Number(n)),
+        // NaN and infinities keep their exact bits
+        None => tagged(tag, json!(bits)),
+    }
+}
+
+// msgpack values map onto JSON as directly as possible, anything JSON can't tell apart
+// becomes a single-key object tagged with a `$` name:
+//   {"$f32": 1.5}                f32, f64 is a plain number
+//   {"$bin": "00ff"}             binary, as hex
+//   {"$str": "c328"}             string that isn't valid UTF-8, as hex
+//   {"$ext": [1, "00ff"]}        ext type and data
+//   {"$map": [[key, value]]}     map with non-string, duplicate or `$` keys
+//   {"$marker": "u32", "value"}  value stored with a bigger encoding than it needs
+fn value_to_json<'a>(
+    value: &Value,
+    markers: &mut impl Iterator<Item = &'a &'static str>,
+) -> serde_json::Value {
+    let marker = markers.next().copied().unwrap_or_default();
+    let json = match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => json!(b),
+        Value::Integer(n) => match n.as_u64() {
+            Some(n) => json!(n),
+            None => json!(n.as_i64()),
+        },
+        Value::F32(f) => float_to_json("$f32", *f as f64, format!("{:#010x}", f.to_bits())),
+        Value::F64(f) => float_to_json("$f64", *f, format!("{:#018x}", f.to_bits())),
+        Value::String(s) => match s.as_str() {
+            Some(s) => json!(s),
+            None => json!({ "$str": to_hex(s.as_bytes()) }),
+        },
+        Value::Binary(bytes) => json!({ "$bin": to_hex(bytes) }),
+        Value::Array(values) => serde_json::Value::Array(
+            values
+                .iter()
+                .map(|value| value_to_json(value, markers))
+                .collect(),
+        ),
+        Value::Map(entries) => {
+            let entries = entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = value_to_json(key, markers);
+                    (key, value_to_json(value, markers))
+                })
+                .collect::<Vec<_>>();
+            // Plain, unique string keys can be a JSON object, anything else stays a list
+            let mut object = Map::new();
+            for (key, value) in &entries {
+                match key {
+                    serde_json::Value::String(key) if !key.starts_with('$') => {
+                        object.insert(key.clone(), value.clone());
+                    }
+                    _ => break,
+                }
+            }
+            if object.len() == entries.len() {
+                serde_json::Value::Object(object)
+            } else {
+                json!({
+                    "$map": entries
+                        .into_iter()
+                        .map(|(key, value)| json!([key, value]))
+                        .collect::<Vec<_>>()
+                })
+            }
+        }
+        Value::Ext(ext_type, data) => json!({ "$ext": [ext_type, to_hex(data)] }),
+    };
+    if marker == canonical_marker(value) {
+        json
+    } else {
+        json!({ "$marker": marker, "value": json })
+    }
+}
+
+fn marker_name(name: &str) -> Result<&'static str> {
+    const MARKERS: [&str; 30] = [
+        "fixint", "fixmap", "fixarray", "fixstr", "nil", "bool", "bin8", "bin16", "bin32", "ext8",
+        "ext16", "ext32", "f32", "f64", "u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64",
+        "fixext", "str8", "str16", "str32", "array16", "array32", "map16", "map32",
+    ];
+    MARKERS
+        .into_iter()
+        .find(|marker| *marker == name)
+        .with_context(|| format!("unknown msgpack marker {:?}", name))
+}
+
+fn json_float(value: &serde_json::Value) -> Result<Result<f64, u64>> {
+    match value {
+        serde_json::Value::Number(n) => Ok(Ok(n.as_f64().context("invalid float")?)),
+        serde_json::Value::String(bits) => {
+            Ok(Err(u64::from_str_radix(bits.trim_start_matches("0x"), 16)?))
+        }
+        value => bail!("invalid float {}", value),
+    }
+}
+
+/// Rebuilds a msgpack value from its JSON form, pushing the markers it should be written with.
+fn value_from_json(json: &serde_json::Value, markers: &mut Vec<&'static str>) -> Result<Value> {
+    if let serde_json::Value::Object(object) = json
+        && let (Some(marker), Some(inner), 2) =
+            (object.get("$marker"), object.get("value"), object.len())
+    {
+        let index = markers.len();
+        let value = value_from_json(inner, markers)?;
+        markers[index] = marker_name(marker.as_str().unwrap_or_default())?;
+        return Ok(value);
+    }
+
+    let index = markers.len();
+    markers.push("");
+    let value = match json {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => Value::from(n),
+            (_, Some(n)) => Value::from(n),
+            _ => Value::F64(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::from(s.as_str()),
+        serde_json::Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| value_from_json(value, markers))
+                .collect::<Result<_>>()?,
+        ),
+        serde_json::Value::Object(object) => match object.iter().next() {
+            Some((tag, value)) if object.len() == 1 && tag.starts_with('$') => {
+                match (tag.as_str(), value) {
+                    ("$f32", value) => match json_float(value)? {
+                        Ok(f) => Value::F32(f as f32),
+                        Err(bits) => Value::F32(f32::from_bits(bits as u32)),
+                    },
+                    ("$f64", value) => match json_float(value)? {
+                        Ok(f) => Value::F64(f),
+                        Err(bits) => Value::F64(f64::from_bits(bits)),
+                    },
+                    ("$bin", serde_json::Value::String(hex)) => Value::Binary(from_hex(hex)?),
+                    ("$str", serde_json::Value::String(hex)) => {
+                        // rmpv only hands out invalid UTF-8 strings from its decoder
+                        let bytes = from_hex(hex)?;
+                        let mut encoded = vec![0xdb];
+                        encoded.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                        encoded.extend_from_slice(&bytes);
+                        rmpv::decode::read_value(&mut Cursor::new(encoded))?
+                    }
+                    ("$ext", serde_json::Value::Array(ext)) => match ext.as_slice() {
+                        [serde_json::Value::Number(ext_type), serde_json::Value::String(hex)] => {
+                            Value::Ext(
+                                ext_type.as_i64().context("invalid ext type")? as i8,
+                                from_hex(hex)?,
+                            )
+                        }
+                        _ => bail!("invalid ext {}", value),
+                    },
+                    ("$map", serde_json::Value::Array(entries)) => Value::Map(
+                        entries
+                            .iter()
+                            .map(|entry| match entry.as_array().map(Vec::as_slice) {
+                                Some([key, value]) => Ok((
+                                    value_from_json(key, markers)?,
+                                    value_from_json(value, markers)?,
+                                )),
+                                _ => bail!("invalid map entry {}", entry),
+                            })
+                            .collect::<Result<_>>()?,
+                    ),
+                    (tag, _) => bail!("invalid {} value {}", tag, value),
+                }
+            }
+            _ => Value::Map(
+                object
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok((
+                            value_from_json(&serde_json::Value::from(key.as_str()), markers)?,
+                            value_from_json(value, markers)
+                                .with_context(|| format!("in {:?}", key))?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+        },
+    };
+    markers[index] = canonical_marker(&value);
+    Ok(value)
+}
+
+/// Whether `path` looks like an ETSB/MONB file, by extension or by the header magic.
+pub fn is_etsb(path: &Path) -> bool {
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("etsb") || ext.eq_ignore_ascii_case("monb"))
+    {
+        return true;
+    }
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| magic == HEADER_MAGIC)
+}
+
+/// Converts an ETSB/MONB file to `output_path`, checking it converts back unchanged.
+pub fn export_etsb(etsb_path: &Path, output_path: &Path, version: &Version) -> Result<()> {
+    let data = std::fs::read(etsb_path)?;
+    let etsb = Etsb::decode(&data, version)?;
+    let recompressed = !matches!(etsb.compression, None | Some(CompressionType::None));
+    if !recompressed && etsb.encode(version)? != data {
+        println!("{:?} won't convert back byte for byte", etsb_path);
+    }
+
+    let json = etsb.to_json();
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(serde_json::to_string_pretty(&json)?.as_bytes())?;
+    Ok(())
+}
+
+/// Converts every ETSB/MONB file under `input_path` to `.ejson`, mirroring the folders into
+/// `output_root`. `a.etsb` becomes `a.etsb.ejson`, files that fail are reported and skipped.
+pub fn export_etsb_tree(input_path: &Path, output_root: &Path, version: &Version) -> Result<()> {
+    let files = file::walk_files(input_path)?
+        .into_par_iter()
+        .filter(|path| is_etsb(path))
+        .collect::<Vec<_>>();
+    println!("Found {} etsb files", files.len());
+
+    let results = files
+        .par_iter()
+        .map(|path| {
+            let relative = path.strip_prefix(input_path).unwrap_or(path);
+            let output_path = file::add_extension(&output_root.join(relative), "ejson");
+            let result = std::fs::create_dir_all(output_path.parent().unwrap_or(output_root))
+                .map_err(Error::from)
+                .and_then(|_| export_etsb(path, &output_path, version));
+            (path, result)
+        })
+        .collect::<Vec<_>>();
+
+    let failed = results
+        .iter()
+        .filter_map(|(path, result)| result.as_ref().err().map(|e| (path, e)))
+        .inspect(|(path, e)| println!("Failed {:?}: {:#}", path, e))
+        .count();
+    println!("{} etsb files, {} failed", results.len(), failed);
+    Ok(())
+}
+
+/// Converts an `.ejson` file back into the ETSB/MONB file it came from.
+pub fn import_etsb(ejson_path: &Path, version: &Version) -> Result<PathBuf> {
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(ejson_path)?)?;
+    let etsb = Etsb::from_json(&json)?;
+    // `a.etsb.ejson` goes back to `a.etsb`
+    let output_path = ejson_path.with_extension("");
+    let mut output_file = File::create(&output_path)?;
+    output_file.write_all(&etsb.encode(version)?)?;
+    Ok(output_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// msgpack -> .ejson text -> msgpack, which has to give back the same bytes.
+    fn round_trip(data: &[u8]) -> serde_json::Value {
+        let etsb = Etsb::decode(data, &Version::ClosedBeta).unwrap();
+        let text = serde_json::to_string_pretty(&etsb.to_json()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        let encoded = Etsb::from_json(&json)
+            .unwrap()
+            .encode(&Version::ClosedBeta)
+            .unwrap();
+        assert_eq!(to_hex(&encoded), to_hex(data), "{}", text);
+        json
+    }
+
+    /// Wraps values in a fixarray so each test covers several at once.
+    fn array(values: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0x90 | values.len() as u8];
+        for value in values {
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    #[test]
+    fn floats() {
+        let json = round_trip(&array(&[
+            &[0xca, 0x3f, 0xc0, 0x00, 0x00],
+            &[0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0],
+            // NaN with a payload and infinities keep their bits
+            &[0xca, 0x7f, 0xc0, 0x12, 0x34],
+            &[0xcb, 0xff, 0xf0, 0, 0, 0, 0, 0, 0],
+        ]));
+        assert_eq!(json["body"][0], json!({ "$f32": 1.5 }));
+        assert_eq!(json["body"][1], json!(1.5));
+    }
+
+    #[test]
+    fn binary_and_strings() {
+        let json = round_trip(&array(&[
+            &[0xa3, b'a', b'b', b'c'],
+            &[0xc4, 0x03, b'a', b'b', b'c'],
+            // Not UTF-8
+            &[0xa2, 0xff, 0xfe],
+            &[0xd9, 0x01, b'x'],
+            &[0xda, 0x00, 0x01, b'x'],
+            &[0xdb, 0x00, 0x00, 0x00, 0x01, b'x'],
+            &[0xc5, 0x00, 0x01, 0x00],
+            &[0xc6, 0x00, 0x00, 0x00, 0x01, 0x00],
+        ]));
+        assert_eq!(json["body"][0], json!("abc"));
+        assert_eq!(json["body"][1], json!({ "$bin": "616263" }));
+    }
+
+    #[test]
+    fn ext_types() {
+        let mut fixext16 = vec![0xd8, 0x02];
+        fixext16.extend_from_slice(&[0xab; 16]);
+        round_trip(&array(&[
+            &[0xd4, 0x05, 0xaa],
+            &[0xd5, 0xff, 0xaa, 0xbb],
+            &fixext16,
+            &[0xc7, 0x03, 0x07, 1, 2, 3],
+            // An ext8 holding a size fixext could have used
+            &[0xc7, 0x01, 0x07, 1],
+            &[0xc8, 0x00, 0x01, 0x07, 1],
+            &[0xc9, 0x00, 0x00, 0x00, 0x01, 0x07, 1],
+        ]));
+    }
+
+    #[test]
+    fn non_canonical_ints() {
+        let json = round_trip(&array(&[
+            &[0x05],
+            &[0xcc, 0x05],
+            &[0xcd, 0x00, 0x05],
+            &[0xce, 0x00, 0x00, 0x00, 0x05],
+            &[0xcf, 0, 0, 0, 0, 0, 0, 0, 0x05],
+            &[0xd0, 0x05],
+            &[0xd1, 0xff, 0xff],
+            &[0xd3, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe],
+            &[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ]));
+        assert_eq!(json["body"][0], json!(5));
+        assert_eq!(json["body"][2], json!({ "$marker": "u16", "value": 5 }));
+    }
+
+    #[test]
+    fn container_sizes() {
+        round_trip(&array(&[
+            &[0xdc, 0x00, 0x01, 0xc0],
+            &[0xdd, 0x00, 0x00, 0x00, 0x01, 0xc3],
+            &[0xde, 0x00, 0x01, 0xa1, b'a', 0x01],
+            &[0xdf, 0x00, 0x00, 0x00, 0x01, 0xa1, b'a', 0xc2],
+        ]));
+    }
+
+    #[test]
+    fn maps_json_objects_cannot_hold() {
+        let json = round_trip(&[
+            0x84, // int key, duplicate key, `$` key and a non-canonical key
+            0x01, 0xc0, //
+            0xa1, b'a', 0x01, //
+            0xa1, b'a', 0x02, //
+            0xa2, b'$', b'f', 0x03,
+        ]);
+        assert!(json["body"]["$map"].is_array());
+        round_trip(&[0x81, 0xd9, 0x01, b'a', 0x01]);
+        let json = round_trip(&[0x81, 0xa1, b'a', 0x01]);
+        assert_eq!(json["body"], json!({ "a": 1 }));
+    }
+
+    #[test]
+    fn header_and_trailing_bytes() {
+        let mut data = vec![0x7c, 0x53, 0xb6, 0xc8, 0x01, 0x00, 0x00, 0x00];
+        data.extend_from_slice(&[0x81, 0xa1, b'a', 0xcd, 0x00, 0x05]);
+        data.extend_from_slice(&[0xff, 0xee]);
+        let json = round_trip(&data);
+        assert_eq!(json["header"], json!({ "version": 1, "flags": 0 }));
+        assert_eq!(json["trailing"], json!("ffee"));
+    }
+
+    #[test]
+    fn edits_keep_their_marker() {
+        let data = [0x81, 0xa1, b'a', 0xcd, 0x00, 0x05];
+        let mut json = Etsb::decode(&data, &Version::ClosedBeta).unwrap().to_json();
+        json["body"]["a"]["value"] = json!(7);
+        let encoded = Etsb::from_json(&json)
+            .unwrap()
+            .encode(&Version::ClosedBeta)
+            .unwrap();
+        assert_eq!(encoded, [0x81, 0xa1, b'a', 0xcd, 0x00, 0x07]);
+    }
+
+    #[test]
+    fn ejson_keeps_the_extension() {
+        let dir = std::env::temp_dir().join(format!("etsb-ejson-{}", std::process::id()));
+        let input = dir.join("in");
+        let output = dir.join("out");
+        std::fs::create_dir_all(input.join("sub")).unwrap();
+        let data = array(&[&[0x01], &[0xa1, b'a']]);
+        std::fs::write(input.join("a.etsb"), &data).unwrap();
+        std::fs::write(input.join("sub").join("a.monb"), &data).unwrap();
+
+        export_etsb_tree(&input, &output, &Version::ClosedBeta).unwrap();
+        for (ejson, restored) in [
+            ("a.etsb.ejson", "a.etsb"),
+            ("sub/a.monb.ejson", "sub/a.monb"),
+        ] {
+            let path = import_etsb(&output.join(ejson), &Version::ClosedBeta).unwrap();
+            assert_eq!(path, output.join(restored));
+            assert_eq!(std::fs::read(&path).unwrap(), data);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
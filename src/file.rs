@@ -1,17 +1,185 @@
-use binrw::{binread, BinRead};
+use binrw::{binread, BinReaderExt, BinResult};
+use rayon::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use super::{material, model};
 #[binread]
 #[derive(Debug, Clone)]
 #[br(magic = b".MESSIAH")]
 pub struct MessiahHeader {
+    #[br(temp)]
+    id: u32,
+    #[br(parse_with = parse_type, args(id))]
     pub data: MessiahTypes,
 }
 
-#[derive(BinRead, Debug, Clone)]
+pub const MATERIAL_ID: u32 = 0x4;
+pub const MODEL_ID: u32 = 0x8;
+
+/// Only material and model ids have been seen and parsed so far. Everything else stays raw,
+/// `--survey-path` over extracted packages lists the other ids, a type gets a variant once
+/// its id and layout are checked against real files.
+#[derive(Debug, Clone)]
 pub enum MessiahTypes {
-    #[br(magic = 0x4_u32)]
     Material(material::MaterialHeader),
-    #[br(magic = 0x8_u32)]
     Model(model::ModelHeader),
+    /// Any type we don't know how to parse yet, kept as raw bytes
+    Unknown {
+        id: u32,
+        bytes: Vec<u8>,
+    },
+}
+
+/// Dispatches on the id, so a known type that fails to parse reports why instead of
+/// falling through to `Unknown`.
+#[binrw::parser(reader, endian)]
+fn parse_type(id: u32) -> BinResult<MessiahTypes> {
+    Ok(match id {
+        MATERIAL_ID => MessiahTypes::Material(reader.read_type(endian)?),
+        MODEL_ID => MessiahTypes::Model(reader.read_type(endian)?),
+        id => {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            MessiahTypes::Unknown { id, bytes }
+        }
+    })
+}
+
+impl MessiahTypes {
+    pub fn id(&self) -> u32 {
+        match self {
+            MessiahTypes::Material(_) => MATERIAL_ID,
+            MessiahTypes::Model(_) => MODEL_ID,
+            MessiahTypes::Unknown { id, .. } => *id,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        type_name(self.id())
+    }
+}
+
+/// Name of a `.MESSIAH` type id, "unknown" for ones without a variant.
+pub fn type_name(id: u32) -> &'static str {
+    match id {
+        MATERIAL_ID => "material",
+        MODEL_ID => "model",
+        _ => "unknown",
+    }
+}
+
+/// Reads the type id of a `.MESSIAH` file without parsing the rest.
+pub fn read_type_id(path: &Path) -> Option<u32> {
+    let mut header = [0; 12];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if &header[..8] != b".MESSIAH" {
+        return None;
+    }
+    Some(u32::from_le_bytes(header[8..].try_into().unwrap()))
+}
+
+/// Counts every `.MESSIAH` type id found under `dir`, with an example file for each.
+pub fn survey_types(dir: &Path) -> std::io::Result<()> {
+    let found = walk_files(dir)?
+        .into_par_iter()
+        .filter_map(|path| read_type_id(&path).map(|id| (id, path)))
+        .collect::<Vec<_>>();
+
+    let mut types: BTreeMap<u32, (usize, PathBuf)> = BTreeMap::new();
+    for (id, path) in found {
+        types.entry(id).or_insert((0, path)).0 += 1;
+    }
+
+    println!("{:>10} {:>8} {:<10} example", "id", "count", "type");
+    for (id, (count, example)) in types {
+        println!(
+            "{:>#10x} {:>8} {:<10} {:?}",
+            id,
+            count,
+            type_name(id),
+            example
+        );
+    }
+    Ok(())
+}
+
+/// Recursively lists every file under `dir`.
+pub fn walk_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Path of `to` relative to the directory `from`.
+pub fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = std::path::absolute(from).unwrap_or(from.to_path_buf());
+    let to = std::path::absolute(to).unwrap_or(to.to_path_buf());
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
+/// `path` with `extension` appended, keeping the one it has, `a.etsb` becomes `a.etsb.csv`.
+pub fn add_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn messiah(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = b".MESSIAH".to_vec();
+        data.extend_from_slice(&id.to_le_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn unknown_id_keeps_bytes() {
+        let header: MessiahHeader = Cursor::new(messiah(0x1234, &[1, 2, 3])).read_le().unwrap();
+        match header.data {
+            MessiahTypes::Unknown { id, bytes } => {
+                assert_eq!(id, 0x1234);
+                assert_eq!(bytes, [1, 2, 3]);
+            }
+            data => panic!("parsed as {}", data.name()),
+        }
+    }
+
+    #[test]
+    fn broken_known_type_is_an_error() {
+        // Too short for a model header, must not fall back to Unknown
+        let result = Cursor::new(messiah(MODEL_ID, &[1, 2, 3])).read_le::<MessiahHeader>();
+        assert!(result.is_err());
+    }
 }
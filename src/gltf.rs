@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::model::MeshData;
+
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+/// Images used by a material, as paths relative to the exported model.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialTextures {
+    pub base_color: Option<PathBuf>,
+    pub normal: Option<PathBuf>,
+    /// Decoded material parameters, stored as the material's extras
+    pub extras: Value,
+}
+
+/// Collects buffer views and accessors over a single binary buffer.
+#[derive(Default)]
+struct GltfBuffer {
+    bin: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl GltfBuffer {
+    fn push_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.bin.len(),
+            "byteLength": data.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.bin.extend_from_slice(data);
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    fn push_floats(&mut self, values: &[f32], kind: &str, width: usize, bounds: bool) -> usize {
+        let data = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let view = self.push_view(&data, Some(ARRAY_BUFFER));
+        let mut accessor = json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": values.len() / width,
+            "type": kind,
+        });
+        if bounds {
+            let mut min = vec![f32::MAX; width];
+            let mut max = vec![f32::MIN; width];
+            for chunk in values.chunks(width) {
+                for (i, v) in chunk.iter().enumerate() {
+                    min[i] = min[i].min(*v);
+                    max[i] = max[i].max(*v);
+                }
+            }
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn push_indices(&mut self, values: &[u32]) -> usize {
+        let data = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let view = self.push_view(&data, Some(ELEMENT_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_INT,
+            "count": values.len(),
+            "type": "SCALAR",
+        }));
+        self.accessors.len() - 1
+    }
+}
+
+/// Writes a binary glTF with one primitive per section, all sharing the vertex streams.
+pub fn write_glb(
+    output_path: &Path,
+    mesh_data: &MeshData,
+    textures: &HashMap<String, MaterialTextures>,
+) -> Result<()> {
+    let MeshData {
+        indices,
+        vertices,
+        sections,
+    } = mesh_data;
+    let mut buffer = GltfBuffer::default();
+    let mut attributes = serde_json::Map::new();
+
+    let positions = vertices
+        .iter()
+        .flat_map(|v| v.position)
+        .collect::<Vec<f32>>();
+    attributes.insert(
+        "POSITION".into(),
+        json!(buffer.push_floats(&positions, "VEC3", 3, true)),
+    );
+
+    if vertices.iter().any(|v| v.normal[0..3] != [0.0; 3]) {
+        let normals = vertices
+            .iter()
+            .flat_map(|v| {
+                if v.normal[0..3] == [0.0; 3] {
+                    [0.0, 0.0, 1.0]
+                } else {
+                    [v.normal[0], v.normal[1], v.normal[2]]
+                }
+            })
+            .collect::<Vec<f32>>();
+        attributes.insert(
+            "NORMAL".into(),
+            json!(buffer.push_floats(&normals, "VEC3", 3, false)),
+        );
+    }
+
+    if vertices.iter().any(|v| v.tangent[0..3] != [0.0; 3]) {
+        let tangents = vertices
+            .iter()
+            .flat_map(|v| {
+                if v.tangent[0..3] == [0.0; 3] {
+                    [1.0, 0.0, 0.0, 1.0]
+                } else {
+                    v.tangent
+                }
+            })
+            .collect::<Vec<f32>>();
+        attributes.insert(
+            "TANGENT".into(),
+            json!(buffer.push_floats(&tangents, "VEC4", 4, false)),
+        );
+    }
+
+    let uv_layer_count = vertices
+        .iter()
+        .map(|v| v.texcoords.len())
+        .max()
+        .unwrap_or(0);
+    for i in 0..uv_layer_count {
+        let uvs = vertices
+            .iter()
+            .flat_map(|v| v.texcoords.get(i).copied().unwrap_or([0.0, 0.0]))
+            .collect::<Vec<f32>>();
+        attributes.insert(
+            format!("TEXCOORD_{}", i),
+            json!(buffer.push_floats(&uvs, "VEC2", 2, false)),
+        );
+    }
+
+    let color_layer_count = vertices.iter().map(|v| v.colors.len()).max().unwrap_or(0);
+    for i in 0..color_layer_count {
+        let colors = vertices
+            .iter()
+            .flat_map(|v| v.colors.get(i).copied().unwrap_or([1.0; 4]))
+            .collect::<Vec<f32>>();
+        attributes.insert(
+            format!("COLOR_{}", i),
+            json!(buffer.push_floats(&colors, "VEC4", 4, false)),
+        );
+    }
+
+    let mut materials = Vec::new();
+    let mut material_indices: HashMap<&String, usize> = HashMap::new();
+    let mut images = Vec::new();
+    let mut gltf_textures = Vec::new();
+    let mut push_texture = |path: &Path| -> usize {
+        images.push(json!({ "uri": path.to_string_lossy().replace('\\', "/") }));
+        gltf_textures.push(json!({ "source": images.len() - 1, "sampler": 0 }));
+        gltf_textures.len() - 1
+    };
+    for name in sections.iter().filter_map(|s| s.material.as_ref()) {
+        if material_indices.contains_key(name) {
+            continue;
+        }
+        let mut material = json!({
+            "name": name,
+            "pbrMetallicRoughness": { "metallicFactor": 0.0 },
+        });
+        if let Some(material_textures) = textures.get(name) {
+            if !material_textures.extras.is_null() {
+                material["extras"] = material_textures.extras.clone();
+            }
+            if let Some(base_color) = &material_textures.base_color {
+                material["pbrMetallicRoughness"]["baseColorTexture"] =
+                    json!({ "index": push_texture(base_color) });
+            }
+            if let Some(normal) = &material_textures.normal {
+                material["normalTexture"] = json!({ "index": push_texture(normal) });
+            }
+        }
+        materials.push(material);
+        material_indices.insert(name, materials.len() - 1);
+    }
+
+    let mut primitives = Vec::new();
+    for section in sections {
+        let start = (section.index_start / 3) as usize;
+        let end = start + (section.index_count / 3) as usize;
+        let section_indices = indices
+            .get(start..end)
+            .with_context(|| {
+                format!(
+                    "section {:?} is outside the {} triangles",
+                    section,
+                    indices.len()
+                )
+            })?
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<u32>>();
+        let mut primitive = json!({
+            "attributes": attributes,
+            "indices": buffer.push_indices(&section_indices),
+            "mode": 4,
+        });
+        if let Some(material) = section.material.as_ref() {
+            primitive["material"] = json!(material_indices[material]);
+        }
+        primitives.push(primitive);
+    }
+
+    buffer.bin.resize(buffer.bin.len().next_multiple_of(4), 0);
+
+    let mut document = json!({
+        "asset": { "version": "2.0", "generator": "dr-messiah" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "name": "mesh", "mesh": 0 }],
+        "meshes": [{ "name": output_path.file_stem().map(|s| s.to_string_lossy()), "primitives": primitives }],
+        "buffers": [{ "byteLength": buffer.bin.len() }],
+        "bufferViews": buffer.buffer_views,
+        "accessors": buffer.accessors,
+    });
+    if !materials.is_empty() {
+        document["materials"] = json!(materials);
+    }
+    if !gltf_textures.is_empty() {
+        document["images"] = json!(images);
+        document["textures"] = json!(gltf_textures);
+        document["samplers"] = json!([{}]);
+    }
+
+    let mut json_chunk = serde_json::to_vec(&document)?;
+    json_chunk.resize(json_chunk.len().next_multiple_of(4), b' ');
+    let total_length = 12 + 8 + json_chunk.len() + 8 + buffer.bin.len();
+
+    let mut out = std::io::BufWriter::new(File::create(output_path)?);
+    out.write_all(b"glTF")?;
+    out.write_all(&2_u32.to_le_bytes())?;
+    out.write_all(&(total_length as u32).to_le_bytes())?;
+    out.write_all(&(json_chunk.len() as u32).to_le_bytes())?;
+    out.write_all(b"JSON")?;
+    out.write_all(&json_chunk)?;
+    out.write_all(&(buffer.bin.len() as u32).to_le_bytes())?;
+    out.write_all(b"BIN\0")?;
+    out.write_all(&buffer.bin)?;
+    Ok(())
+}
@@ -0,0 +1,406 @@
+use anyhow::{bail, Result};
+use binrw::{BinRead, BinReaderExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    io::{Cursor, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{
+    compression::{self, CompressionType},
+    etsb::{self, EtsbHeader},
+    file,
+    material::MaterialHeader,
+    model::{self, ModelHeader},
+    mpk::{MpkInfo, ResourcesMpkInfo},
+    texture,
+    version::Version,
+};
+
+/// How many unconsumed bytes to hex dump before eliding the rest.
+const HEX_DUMP_LIMIT: usize = 0x100;
+
+/// A byte range of an inspected file and what was parsed from it.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub name: String,
+    pub value: Value,
+}
+
+impl Region {
+    pub fn parsed<T: Serialize>(start: u64, end: u64, name: &str, value: &T) -> Self {
+        Self {
+            start,
+            end,
+            name: name.to_string(),
+            value: serde_json::to_value(value).unwrap_or(Value::Null),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Inspection {
+    pub kind: String,
+    pub size: u64,
+    pub regions: Vec<Region>,
+    pub error: Option<String>,
+    /// Decompressed payload of a compression container and its inspection
+    pub nested: Option<(Vec<u8>, Box<Inspection>)>,
+}
+
+type Reader<'a> = Cursor<&'a [u8]>;
+
+/// Reads a `T` at the current position and records the bytes it covered.
+/// Leaves the reader where it was if it doesn't parse.
+fn read_region<T>(reader: &mut Reader, name: &str, regions: &mut Vec<Region>) -> Result<T>
+where
+    T: BinRead + Serialize,
+    for<'a> T::Args<'a>: Default,
+{
+    let start = reader.position();
+    match reader.read_le::<T>() {
+        Ok(value) => {
+            regions.push(Region::parsed(start, reader.position(), name, &value));
+            Ok(value)
+        }
+        Err(e) => {
+            reader.set_position(start);
+            Err(e.into())
+        }
+    }
+}
+
+/// Works out what `data` is and maps out as much of it as we can parse.
+pub fn inspect_bytes(data: &[u8], file_name: &str, version: &Version) -> Inspection {
+    let mut inspection = Inspection {
+        kind: "unknown".to_string(),
+        size: data.len() as u64,
+        regions: Vec::new(),
+        error: None,
+        nested: None,
+    };
+    let mut reader = Cursor::new(data);
+
+    let result = if file_name.ends_with(".mpkinfo") {
+        inspect_mpkinfo(&mut reader, file_name, &mut inspection)
+    } else if data.starts_with(b".MESSIAH") {
+        inspect_messiah(&mut reader, &mut inspection)
+    } else if data.starts_with(&etsb::HEADER_MAGIC) {
+        inspect_etsb(&mut reader, version, &mut inspection)
+    } else if let Some(compression_type) = (data.len() >= 8)
+        .then(|| compression::get_compression_type(data))
+        .flatten()
+    {
+        inspect_container(data, compression_type, version, &mut inspection)
+    } else if let Some(regions) = texture::texture_regions(&mut reader, data.len() as u64) {
+        inspection.kind = "texture".to_string();
+        inspection.regions = regions;
+        Ok(())
+    } else {
+        Ok(())
+    };
+    if let Err(e) = result {
+        inspection.error = Some(format!("{:#}", e));
+    }
+    inspection
+}
+
+fn inspect_container(
+    data: &[u8],
+    compression_type: CompressionType,
+    version: &Version,
+    inspection: &mut Inspection,
+) -> Result<()> {
+    inspection.kind = format!("{:?} container", compression_type);
+    let decompressed_size = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    inspection.regions.push(Region::parsed(
+        0,
+        8,
+        "compression header",
+        &json!({
+            "type": format!("{:?}", compression_type),
+            "decompressed_size": decompressed_size,
+        }),
+    ));
+    inspection.regions.push(Region::parsed(
+        8,
+        data.len() as u64,
+        "compressed payload",
+        &Value::Null,
+    ));
+
+    let decompressed = compression::decompress(version, compression_type, data)?;
+    let nested = inspect_bytes(&decompressed, "", version);
+    inspection.nested = Some((decompressed, Box::new(nested)));
+    Ok(())
+}
+
+fn inspect_messiah(reader: &mut Reader, inspection: &mut Inspection) -> Result<()> {
+    let size = inspection.size;
+    let regions = &mut inspection.regions;
+    reader.seek(SeekFrom::Start(8))?;
+    let id = reader.read_le::<u32>()?;
+    regions.push(Region::parsed(
+        0,
+        12,
+        ".MESSIAH header",
+        &json!({ "type": id, "name": file::type_name(id) }),
+    ));
+    inspection.kind = format!(".MESSIAH {}", file::type_name(id));
+
+    match id {
+        file::MATERIAL_ID => {
+            read_region::<MaterialHeader>(reader, "MaterialHeader", regions)?;
+        }
+        file::MODEL_ID => {
+            let mut header = Some(read_region::<ModelHeader>(reader, "ModelHeader", regions)?);
+            let mut lod = 0;
+            while let Some(model) = header {
+                let start = reader.position();
+                let (mut value, result) = model::inspect_lod(reader, &model, size);
+                if let Some(lod) = value.as_object_mut() {
+                    lod.remove("header");
+                }
+                let end = value["end"].as_u64().unwrap_or(start);
+                regions.push(Region::parsed(
+                    start,
+                    end,
+                    &format!("LOD {} streams", lod),
+                    &value,
+                ));
+                result?;
+
+                let start = reader.position();
+                header = model::read_lod_header(reader, &model, size)?;
+                lod += 1;
+                if let Some(model) = &header {
+                    regions.push(Region::parsed(
+                        start,
+                        reader.position(),
+                        &format!("LOD {} ModelHeader", lod),
+                        model,
+                    ));
+                }
+            }
+        }
+        // Unknown types are left to the hex dump
+        _ => {}
+    }
+    Ok(())
+}
+
+fn describe_msgpack(value: &rmpv::Value) -> Value {
+    match value {
+        rmpv::Value::Map(entries) => json!({
+            "map": entries.len(),
+            "keys": entries.iter().take(16).map(|(k, _)| k.to_string()).collect::<Vec<_>>(),
+        }),
+        rmpv::Value::Array(values) => json!({ "array": values.len() }),
+        value => json!(value.to_string()),
+    }
+}
+
+fn inspect_msgpack(reader: &mut Reader, inspection: &mut Inspection) -> Result<()> {
+    let start = reader.position();
+    let value = rmpv::decode::read_value(reader)?;
+    inspection.regions.push(Region::parsed(
+        start,
+        reader.position(),
+        "msgpack body",
+        &describe_msgpack(&value),
+    ));
+    Ok(())
+}
+
+fn inspect_etsb(reader: &mut Reader, version: &Version, inspection: &mut Inspection) -> Result<()> {
+    inspection.kind = "ETSB".to_string();
+    read_region::<EtsbHeader>(reader, "ETSB header", &mut inspection.regions)?;
+    let data = *reader.get_ref();
+    let offset = reader.position();
+    let body = &data[offset as usize..];
+    let Some(compression_type) = (body.len() >= 8)
+        .then(|| compression::get_compression_type(body))
+        .flatten()
+    else {
+        return inspect_msgpack(reader, inspection);
+    };
+
+    let mut container = Inspection {
+        kind: String::new(),
+        size: body.len() as u64,
+        regions: Vec::new(),
+        error: None,
+        nested: None,
+    };
+    let result = inspect_container(body, compression_type, version, &mut container);
+    inspection
+        .regions
+        .extend(container.regions.into_iter().map(|region| Region {
+            start: region.start + offset,
+            end: region.end + offset,
+            ..region
+        }));
+    result?;
+
+    // The payload is a bare msgpack body, not something inspect_bytes recognises
+    if let Some((payload, _)) = container.nested {
+        let mut nested = Inspection {
+            kind: "msgpack".to_string(),
+            size: payload.len() as u64,
+            regions: Vec::new(),
+            error: None,
+            nested: None,
+        };
+        if let Err(e) = inspect_msgpack(&mut Cursor::new(&payload), &mut nested) {
+            nested.error = Some(format!("{:#}", e));
+        }
+        inspection.nested = Some((payload, Box::new(nested)));
+    }
+    Ok(())
+}
+
+fn inspect_mpkinfo(
+    reader: &mut Reader,
+    file_name: &str,
+    inspection: &mut Inspection,
+) -> Result<()> {
+    if matches!(file_name, "Resources.mpkinfo" | "Engine.mpkinfo") {
+        inspection.kind = "ResourcesMpkInfo".to_string();
+        let resources: ResourcesMpkInfo = reader.read_le()?;
+        let records = resources.records.len() as u64;
+        inspection.regions.push(Region::parsed(
+            0,
+            8,
+            "ResourcesMpkInfo header",
+            &json!({ "version": 2, "record_num": records }),
+        ));
+        if records == 0 {
+            return Ok(());
+        }
+        // Records are fixed size
+        let record_size = (reader.position() - 8) / records;
+        for (i, record) in resources.records.iter().enumerate() {
+            let start = 8 + i as u64 * record_size;
+            inspection.regions.push(Region::parsed(
+                start,
+                start + record_size,
+                &format!("record {}", i),
+                record,
+            ));
+        }
+    } else {
+        inspection.kind = "MpkInfo".to_string();
+        let mut count = 0;
+        while read_region::<MpkInfo>(
+            reader,
+            &format!("MpkInfo {}", count),
+            &mut inspection.regions,
+        )
+        .is_ok()
+        {
+            count += 1;
+        }
+        if count == 0 {
+            bail!("no MpkInfo entries");
+        }
+    }
+    Ok(())
+}
+
+fn hex_dump(data: &[u8], offset: u64, indent: &str) {
+    for (i, line) in data.chunks(16).take(HEX_DUMP_LIMIT / 16).enumerate() {
+        let hex = line
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|b| {
+                if b.is_ascii_graphic() {
+                    *b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        println!(
+            "{}    {:08x}  {:<47}  |{}|",
+            indent,
+            offset + i as u64 * 16,
+            hex,
+            ascii
+        );
+    }
+    if data.len() > HEX_DUMP_LIMIT {
+        println!(
+            "{}    ... {:#x} more bytes",
+            indent,
+            data.len() - HEX_DUMP_LIMIT
+        );
+    }
+}
+
+/// Prints every region in file order, hex dumping the bytes nothing parsed.
+pub fn print_inspection(inspection: &Inspection, data: &[u8], indent: &str) {
+    println!(
+        "{}{} ({:#x} bytes)",
+        indent, inspection.kind, inspection.size
+    );
+
+    let mut regions = inspection.regions.iter().collect::<Vec<_>>();
+    regions.sort_by_key(|region| region.start);
+    let mut position = 0;
+    let print_unconsumed = |start: u64, end: u64| {
+        println!(
+            "{}{:#010x}..{:#010x}  unconsumed ({:#x} bytes)",
+            indent,
+            start,
+            end,
+            end - start
+        );
+        hex_dump(&data[start as usize..end as usize], start, indent);
+    };
+    for region in regions {
+        if region.start > position {
+            print_unconsumed(position, region.start);
+        }
+        println!(
+            "{}{:#010x}..{:#010x}  {}",
+            indent, region.start, region.end, region.name
+        );
+        if !region.value.is_null() {
+            let value = serde_json::to_string_pretty(&region.value).unwrap_or_default();
+            for line in value.lines() {
+                println!("{}    {}", indent, line);
+            }
+        }
+        position = position.max(region.end);
+    }
+    if inspection.size > position {
+        print_unconsumed(position, inspection.size);
+    }
+
+    if let Some(error) = &inspection.error {
+        println!("{}error: {}", indent, error);
+    }
+    if let Some((payload, nested)) = &inspection.nested {
+        println!("{}decompressed:", indent);
+        print_inspection(nested, payload, &format!("{}    ", indent));
+    }
+}
+
+/// Inspects a file and prints a structured view of it.
+pub fn inspect_file(path: &Path, version: &Version) -> Result<()> {
+    let data = std::fs::read(path)?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let inspection = inspect_bytes(&data, &file_name, version);
+    print_inspection(&inspection, &data, "");
+    Ok(())
+}
@@ -1,21 +1,28 @@
 #![feature(let_chains)]
 mod compression;
+mod etsb;
 mod file;
+mod gltf;
+mod inspect;
 mod material;
 mod model;
 mod mpk;
+mod table;
 mod texture;
 mod version;
 
 use binrw::BinReaderExt;
 use clap::Parser;
+use model::ModelFormat;
 use mpk::{MpkInfo, ResourceList, ResourcesMpkInfo};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::sync::Mutex;
+use table::TableFormat;
+use texture::NormalConvention;
 use version::Version;
 
 #[derive(clap::Parser, Debug, Clone)]
@@ -32,24 +39,61 @@ struct Args {
     #[arg(short)]
     output_path: Option<String>,
 
-    /// Convert all etsb in path to json for readability
+    /// Convert an etsb file, or every one under a directory (mirrored into -o), to json for readability
     #[arg(short)]
     etsb_path: Option<String>,
 
-    /// Convert model file to cast file
+    /// Convert .ejson files from -e back to etsb/monb, a single file or every one in a directory
+    #[arg(short = 'j')]
+    ejson_path: Option<String>,
+
+    /// Flatten an etsb file, or every one under a directory, into tables of game data
+    #[arg(short)]
+    gamedata_path: Option<String>,
+
+    /// Output format for -g, csv files mirrored into -o or a single sqlite database at -o
+    #[arg(long, value_enum, default_value_t = TableFormat::Csv)]
+    table_format: TableFormat,
+
+    /// Convert model file to cast file, material file to json, or every model in a directory (mirrored into -o)
     #[arg(short)]
     model_path: Option<String>,
 
+    /// Extracted resources to resolve model materials and textures from
+    #[arg(short)]
+    resource_root: Option<String>,
+
+    /// Output format for converted models
+    #[arg(short, value_enum, default_value_t = ModelFormat::Cast)]
+    format: ModelFormat,
+
+    /// Only convert the first LOD of a model
+    #[arg(short)]
+    lod0_only: bool,
+
+    /// Print a structured, offset annotated view of any resource, model stream statistics
+    /// are also written to .inspect.json
+    #[arg(short)]
+    inspect_path: Option<String>,
+
+    /// Count the `.MESSIAH` resource types found in an extracted directory
+    #[arg(long)]
+    survey_path: Option<String>,
+
     /// Manually decompress a file
     #[arg(short)]
     decompress_path: Option<String>,
 
-    /// Manually convert a texture to dds
+    /// Manually convert a texture to png, or every texture in a directory (mirrored into -o)
     #[arg(short)]
     texture_path: Option<String>,
 
     #[arg(short)]
     version: Option<Version>,
+
+    /// Green channel convention for exported normal maps
+    #[arg(short, value_enum, default_value_t = NormalConvention::DirectX)]
+    normal_convention: NormalConvention,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -77,7 +121,37 @@ fn main() -> anyhow::Result<()> {
     println!("version: {:#?}", version);
 
     if let Some(texture_path) = args.texture_path {
-        texture::export_texture(version, &texture_path)?;
+        if PathBuf::from(&texture_path).is_dir() {
+            let texture_path = PathBuf::from(texture_path);
+            let output_root = args
+                .output_path
+                .as_ref()
+                .map_or(texture_path.clone(), PathBuf::from);
+            texture::export_texture_tree(
+                version,
+                &texture_path,
+                &output_root,
+                args.normal_convention,
+            )?;
+        } else {
+            texture::export_texture(version, &texture_path, args.normal_convention)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(inspect_path) = args.inspect_path {
+        let inspect_path = PathBuf::from(inspect_path);
+        inspect::inspect_file(&inspect_path, version)?;
+        if file::read_type_id(&inspect_path) == Some(file::MODEL_ID) {
+            let inspection = serde_json::to_string_pretty(&model::inspect_model(&inspect_path)?)?;
+            let mut output_file = File::create(inspect_path.with_extension("inspect.json"))?;
+            output_file.write_all(inspection.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    if let Some(survey_path) = args.survey_path {
+        file::survey_types(&PathBuf::from(survey_path))?;
         return Ok(());
     }
 
@@ -104,38 +178,71 @@ fn main() -> anyhow::Result<()> {
     }
 
     if let Some(model_path) = args.model_path {
-        model::export_model(&model_path)?;
+        let options = model::ModelExportOptions {
+            lod0_only: args.lod0_only,
+            format: args.format,
+            resource_root: args.resource_root.as_ref().map(PathBuf::from),
+        };
+        if PathBuf::from(&model_path).is_dir() {
+            let model_path = PathBuf::from(model_path);
+            let output_root = args
+                .output_path
+                .as_ref()
+                .map_or(model_path.clone(), PathBuf::from);
+            let textures = model::TextureOutput {
+                version,
+                normal_convention: args.normal_convention,
+            };
+            model::export_model_tree(&model_path, &output_root, &options, &textures)?;
+        } else {
+            model::export_model(&model_path, &options)?;
+        }
         return Ok(());
     }
 
-    if args.etsb_path.is_some() {
-        for file in std::fs::read_dir(args.etsb_path.unwrap())? {
-            let file = file?;
-            let file_path = file.path();
-            if file_path.is_dir() {
-                continue;
-            }
-            println!("{:?}", file_path);
-            let mut file = File::open(file_path.clone())?;
-            if file.metadata()?.len() < 4 {
-                continue;
-            }
-            if matches!(
-                file_path.extension().unwrap().to_str().unwrap(),
-                "etsb" | "monb"
-            ) || file.read_le::<u16>()? == 0x537C
-            {
-                let mut data = Vec::new();
-                file.seek(SeekFrom::Start(0))?;
-                file.read_to_end(&mut data)?;
-                if data[0x0..0x4] == [0x7c, 0x53, 0xb6, 0xc8] {
-                    data = data[0x8..].to_vec();
-                }
-                let etsb: serde_json::Value = rmp_serde::from_slice(&data)?;
-                let json = serde_json::to_string_pretty(&etsb)?;
-                let mut output_file = File::create(file_path.with_extension("ejson"))?;
-                output_file.write_all(json.as_bytes())?;
-            }
+    if let Some(ejson_path) = args.ejson_path {
+        let ejson_path = PathBuf::from(ejson_path);
+        let files = if ejson_path.is_dir() {
+            file::walk_files(&ejson_path)?
+                .into_iter()
+                .filter(|path| path.extension().is_some_and(|ext| ext == "ejson"))
+                .collect()
+        } else {
+            vec![ejson_path]
+        };
+        for file_path in files {
+            let output_path = etsb::import_etsb(&file_path, version)?;
+            println!("{:?} -> {:?}", file_path, output_path);
+        }
+        return Ok(());
+    }
+
+    if let Some(gamedata_path) = args.gamedata_path {
+        let gamedata_path = PathBuf::from(gamedata_path);
+        let output_path = match (&args.output_path, args.table_format) {
+            (Some(output_path), _) => PathBuf::from(output_path),
+            (None, TableFormat::Sqlite) => gamedata_path.with_extension("sqlite"),
+            (None, TableFormat::Csv) if gamedata_path.is_dir() => gamedata_path.clone(),
+            (None, TableFormat::Csv) => gamedata_path
+                .parent()
+                .map(PathBuf::from)
+                .unwrap_or_default(),
+        };
+        table::export_tables(&gamedata_path, &output_path, args.table_format, version)?;
+        return Ok(());
+    }
+
+    if let Some(etsb_path) = args.etsb_path {
+        let etsb_path = PathBuf::from(etsb_path);
+        if etsb_path.is_dir() {
+            let output_root = args
+                .output_path
+                .as_ref()
+                .map_or(etsb_path.clone(), PathBuf::from);
+            etsb::export_etsb_tree(&etsb_path, &output_root, version)?;
+        } else {
+            let output_path = file::add_extension(&etsb_path, "ejson");
+            etsb::export_etsb(&etsb_path, &output_path, version)?;
         }
         return Ok(());
     }
@@ -1,8 +1,22 @@
-use binrw::binread;
-use std::io::SeekFrom;
+use anyhow::{bail, Result};
+use binrw::{binread, BinReaderExt};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    file::{self, MessiahHeader, MessiahTypes},
+    texture,
+};
 
 #[binread]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct MaterialHeader {
     #[br(seek_before=SeekFrom::Current(0x3), temp)]
     _size: u16,
@@ -18,3 +32,370 @@ pub struct MaterialHeader {
     #[br(map = |s: Vec<u8>| String::from_utf8_lossy(&s).to_string(), count = _size)]
     pub data: String,
 }
+
+/// Decoded material payload.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Material {
+    pub id1: String,
+    pub id2: String,
+    pub unk1: u32,
+    pub shader: Option<String>,
+    /// Texture slot name to texture resource reference
+    pub textures: BTreeMap<String, String>,
+    pub scalars: BTreeMap<String, f32>,
+    pub vectors: BTreeMap<String, Vec<f32>>,
+    /// Anything else, blend modes, culling, flags...
+    pub render_state: BTreeMap<String, Value>,
+}
+
+impl Material {
+    fn insert(&mut self, key: &str, value: Value) {
+        let lower = key.to_lowercase();
+        match value {
+            Value::String(s) if lower.contains("shader") || lower == "effect" => {
+                self.shader = Some(s);
+            }
+            Value::String(s)
+                if lower.contains("tex")
+                    || lower.contains("map")
+                    || lower.contains("sampler")
+                    || s.contains('/')
+                    || s.contains('\\') =>
+            {
+                self.textures.insert(key.to_string(), s);
+            }
+            Value::Number(n) if n.as_f64().is_some() => {
+                self.scalars
+                    .insert(key.to_string(), n.as_f64().unwrap() as f32);
+            }
+            Value::Array(ref values)
+                if !values.is_empty() && values.iter().all(Value::is_number) =>
+            {
+                self.vectors.insert(
+                    key.to_string(),
+                    values
+                        .iter()
+                        .filter_map(Value::as_f64)
+                        .map(|v| v as f32)
+                        .collect(),
+                );
+            }
+            // Nested groups (e.g. "Textures": {...}) are flattened into their parent
+            Value::Object(map) => {
+                for (k, v) in map {
+                    self.insert(&k, v);
+                }
+            }
+            value => {
+                self.render_state.insert(key.to_string(), value);
+            }
+        }
+    }
+}
+
+/// Parses a `key = value` (or `key: value`) line value into JSON.
+fn parse_text_value(value: &str) -> Value {
+    let value = value.trim().trim_matches('"');
+    if let Ok(n) = value.parse::<f64>() {
+        return Value::from(n);
+    }
+    if let Ok(b) = value.parse::<bool>() {
+        return Value::from(b);
+    }
+    let numbers = value
+        .split([',', ' '])
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim_matches(['(', ')', '[', ']']).parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>();
+    match numbers {
+        Ok(numbers) if numbers.len() > 1 => Value::from(numbers),
+        _ => Value::from(value),
+    }
+}
+
+impl From<&MaterialHeader> for Material {
+    fn from(header: &MaterialHeader) -> Self {
+        let mut material = Material {
+            id1: header.id1.clone(),
+            id2: header.id2.clone(),
+            unk1: header.unk1,
+            ..Default::default()
+        };
+
+        // Payloads that aren't a JSON object are read as `key = value` lines, best effort
+        if let Ok(Value::Object(map)) = serde_json::from_str::<Value>(&header.data) {
+            for (key, value) in map {
+                material.insert(&key, value);
+            }
+            return material;
+        }
+        for line in header.data.split(['\n', ';']) {
+            let Some((key, value)) = line.split_once('=').or_else(|| line.split_once(':')) else {
+                continue;
+            };
+            material.insert(key.trim(), parse_text_value(value));
+        }
+        material
+    }
+}
+
+pub fn load_material(material_path: &Path) -> Result<Material> {
+    let mut file = File::open(material_path)?;
+    let fileheader: MessiahHeader = file.read_le()?;
+    match fileheader.data {
+        MessiahTypes::Material(material) => Ok(Material::from(&material)),
+        _ => bail!("{:?} is not a material", material_path),
+    }
+}
+
+/// Writes a material as JSON next to the source file.
+pub fn export_material(material_path: &str) -> Result<()> {
+    let material = load_material(Path::new(material_path))?;
+    println!("{:#?}", material);
+    let mut output_file = File::create(PathBuf::from(material_path).with_extension("json"))?;
+    output_file.write_all(serde_json::to_string_pretty(&material)?.as_bytes())?;
+    Ok(())
+}
+
+/// Lowercase words of a slot name, split at separators, digits and camel case humps, so
+/// `g_BaseColorMap0` is `g`, `base`, `color`, `map` and `AOTex` is `ao`, `tex`.
+fn slot_words(slot: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for part in slot.split(|c: char| !c.is_ascii_alphabetic()) {
+        let chars = part.chars().collect::<Vec<_>>();
+        let mut start = 0;
+        for i in 1..chars.len() {
+            let hump = chars[i].is_uppercase()
+                && (chars[i - 1].is_lowercase()
+                    || chars.get(i + 1).is_some_and(|c| c.is_lowercase()));
+            if hump {
+                words.push(chars[start..i].iter().collect::<String>().to_lowercase());
+                start = i;
+            }
+        }
+        if start < chars.len() {
+            words.push(chars[start..].iter().collect::<String>().to_lowercase());
+        }
+    }
+    words
+}
+
+/// Cast material slot for an engine texture slot name.
+pub fn cast_slot(slot: &str) -> Option<&'static str> {
+    // A word matches as is or with a texture suffix, `normalmap` is `normal`
+    let words = slot_words(slot);
+    let has = |names: &[&str]| {
+        words.iter().any(|word| {
+            let word = ["map", "tex", "texture", "sampler"]
+                .iter()
+                .find_map(|suffix| word.strip_suffix(suffix).filter(|w| !w.is_empty()))
+                .unwrap_or(word);
+            names.contains(&word)
+        })
+    };
+    // Specific slots first, "EmissiveColor" or "SpecularColor" aren't albedo
+    if has(&["normal", "bump"]) {
+        Some("normal")
+    } else if has(&["emissive", "emission"]) {
+        Some("emissive")
+    } else if has(&["spec", "specular"]) {
+        Some("specular")
+    } else if has(&["rough", "roughness"]) {
+        Some("roughness")
+    } else if has(&["metal", "metallic", "metalness"]) {
+        Some("metal")
+    } else if has(&["ao", "occlusion"]) {
+        Some("ao")
+    } else if has(&["albedo", "diffuse", "base", "basecolor", "color", "colour"]) {
+        Some("albedo")
+    } else {
+        None
+    }
+}
+
+/// A material with its texture slots pointing at exported images.
+#[derive(Debug, Clone)]
+pub struct ResolvedMaterial {
+    pub material: Material,
+    pub textures: Vec<(String, PathBuf)>,
+}
+
+/// Every material and texture found under a resource root.
+pub struct MaterialLibrary {
+    materials: HashMap<String, Material>,
+    textures: HashMap<String, PathBuf>,
+}
+
+fn normalize_reference(reference: &str) -> String {
+    reference.replace('\\', "/").to_lowercase()
+}
+
+impl MaterialLibrary {
+    pub fn load(root: &Path) -> Result<Self> {
+        let paths = file::walk_files(root)?;
+
+        // Only textures are registered, so `rock.mat` or `rock.model` can't take the key of
+        // the `rock` texture a material points at
+        let texture_paths = paths
+            .par_iter()
+            .filter(|path| texture::probe_texture(path).is_some())
+            .collect::<Vec<_>>();
+        let mut textures = HashMap::new();
+        for path in texture_paths {
+            let relative = path.strip_prefix(root).unwrap_or(path).with_extension("");
+            textures.insert(
+                normalize_reference(&relative.to_string_lossy()),
+                path.clone(),
+            );
+            if let Some(stem) = path.file_stem() {
+                textures
+                    .entry(normalize_reference(&stem.to_string_lossy()))
+                    .or_insert_with(|| path.clone());
+            }
+        }
+
+        let found = paths
+            .par_iter()
+            .filter(|path| file::read_type_id(path) == Some(file::MATERIAL_ID))
+            .filter_map(|path| load_material(path).ok().map(|m| (path, m)))
+            .collect::<Vec<_>>();
+
+        let mut materials = HashMap::new();
+        for (path, material) in found {
+            let relative = path.strip_prefix(root).unwrap_or(path).with_extension("");
+            for key in [
+                material.id1.clone(),
+                material.id2.clone(),
+                relative.to_string_lossy().to_string(),
+                path.file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            ] {
+                if !key.is_empty() {
+                    materials
+                        .entry(normalize_reference(&key))
+                        .or_insert_with(|| material.clone());
+                }
+            }
+        }
+        println!("Found {} material references", materials.len());
+
+        Ok(Self {
+            materials,
+            textures,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Material> {
+        self.materials.get(&normalize_reference(name))
+    }
+
+    /// Finds the extracted texture for a texture reference.
+    pub fn find_texture(&self, reference: &str) -> Option<&PathBuf> {
+        let reference = normalize_reference(reference);
+        let reference = Path::new(&reference).with_extension("");
+        self.textures
+            .get(&reference.to_string_lossy().to_string())
+            .or_else(|| {
+                reference
+                    .file_name()
+                    .and_then(|stem| self.textures.get(&stem.to_string_lossy().to_string()))
+            })
+    }
+
+    /// Finds the image exported for a texture reference, the png `-t` writes next to it.
+    pub fn resolve_texture(&self, reference: &str) -> Option<PathBuf> {
+        Some(self.find_texture(reference)?.with_extension("png"))
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<ResolvedMaterial> {
+        let material = self.get(name)?.clone();
+        let mut textures = Vec::new();
+        for (slot, reference) in &material.textures {
+            match self.resolve_texture(reference) {
+                Some(path) => textures.push((slot.clone(), path)),
+                None => println!("Texture {} ({}) not found", reference, slot),
+            }
+        }
+        Some(ResolvedMaterial { material, textures })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cast_slots() {
+        for (slot, expected) in [
+            ("BaseColorMap", Some("albedo")),
+            ("basecolormap", Some("albedo")),
+            ("DiffuseTex", Some("albedo")),
+            ("AlbedoColor", Some("albedo")),
+            ("NormalMap", Some("normal")),
+            ("g_normalmap0", Some("normal")),
+            ("EmissiveColor", Some("emissive")),
+            ("EmissiveColorMap", Some("emissive")),
+            ("SpecularColor", Some("specular")),
+            ("SpecColorTex", Some("specular")),
+            ("RoughnessBase", Some("roughness")),
+            ("MetallicBase", Some("metal")),
+            ("AOColor", Some("ao")),
+            ("AOTex", Some("ao")),
+            ("OcclusionMap", Some("ao")),
+            ("MaskTex", None),
+            // "ao" inside other words isn't occlusion
+            ("ChaosTex", None),
+            ("MaoMap", None),
+            ("Raoughness", None),
+        ] {
+            assert_eq!(cast_slot(slot), expected, "{}", slot);
+        }
+    }
+
+    fn header(data: &str) -> MaterialHeader {
+        MaterialHeader {
+            id1: "id1".to_string(),
+            id2: "id2".to_string(),
+            unk1: 1,
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn json_payload() {
+        let material = Material::from(&header(
+            r#"{
+                "Shader": "pbr_standard",
+                "Textures": { "BaseColorMap": "char/hero/body_d", "NormalMap": "char/hero/body_n" },
+                "Roughness": 0.5,
+                "TintColor": [1, 0.5, 0.25, 1],
+                "TwoSided": true,
+                "Detail": "levels\\common\\detail"
+            }"#,
+        ));
+        assert_eq!(material.shader.as_deref(), Some("pbr_standard"));
+        assert_eq!(
+            material.textures.keys().collect::<Vec<_>>(),
+            ["BaseColorMap", "Detail", "NormalMap"]
+        );
+        assert_eq!(material.textures["BaseColorMap"], "char/hero/body_d");
+        assert_eq!(material.scalars["Roughness"], 0.5);
+        assert_eq!(material.vectors["TintColor"], [1.0, 0.5, 0.25, 1.0]);
+        assert_eq!(material.render_state["TwoSided"], Value::Bool(true));
+        assert_eq!(material.id1, "id1");
+    }
+
+    #[test]
+    fn line_payload() {
+        let material = Material::from(&header(
+            "shader = pbr_standard\nBaseColorMap: char/hero/body_d\nRoughness = 0.5;Tint = (1, 0.5, 0.25)\nBlend = additive\nbroken line",
+        ));
+        assert_eq!(material.shader.as_deref(), Some("pbr_standard"));
+        assert_eq!(material.textures["BaseColorMap"], "char/hero/body_d");
+        assert_eq!(material.scalars["Roughness"], 0.5);
+        assert_eq!(material.vectors["Tint"], [1.0, 0.5, 0.25]);
+        assert_eq!(material.render_state["Blend"], Value::from("additive"));
+        assert_eq!(material.render_state.len(), 1);
+    }
+}
@@ -1,13 +1,28 @@
-use std::{fs::File, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Error, Result};
+use anyhow::{bail, Context, Error, Result};
 use binrw::{binread, BinRead, BinReaderExt};
+use half::f16;
 use porter_cast::{CastFile, CastId, CastNode, CastPropertyId};
 use porter_math::{Vector2, Vector3};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
 
-use crate::file::{MessiahHeader, MessiahTypes};
+use crate::{
+    file::{self, MessiahHeader, MessiahTypes},
+    gltf,
+    material::{self, MaterialLibrary, ResolvedMaterial},
+    texture::{self, NormalConvention},
+    version::Version,
+};
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 pub struct ModelHeader {
     pub _unk0: u32,
     pub _unk4: u32,
@@ -15,12 +30,60 @@ pub struct ModelHeader {
     pub index_count: u32,
     #[br(count = 4)]
     pub buffer_layouts: Vec<BufferLayout>,
-    pub _unk_floats: [f32; 10],
-    pub _unk2: [u32; 4],
+    pub bounds: Bounds,
+    pub section: SectionRange,
+}
+
+// ?? the last four header words, they look like the draw range of the mesh's first section.
+// Not used for export, sections come from `read_sections`
+#[derive(BinRead, Debug, Clone, Copy, Serialize)]
+pub struct SectionRange {
+    pub index_start: u32,
+    pub index_count: u32,
+    pub vertex_start: u32,
+    pub vertex_count: u32,
+}
+
+// ?? min/max match the positions on every model checked, center/radius are a guess
+#[derive(BinRead, Debug, Clone, Copy, Serialize)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+    pub center: [f32; 3],
+    pub radius: f32,
+}
+
+impl Bounds {
+    /// Whether `position` lies inside the box, with some slack for quantized positions.
+    pub fn contains(&self, position: [f32; 3]) -> bool {
+        let slack = (0..3)
+            .map(|i| self.max[i] - self.min[i])
+            .fold(0.0, f32::max)
+            * 0.01;
+        (0..3).all(|i| position[i] >= self.min[i] - slack && position[i] <= self.max[i] + slack)
+    }
+
+    /// Whether center and radius agree with the box, i.e. the header layout is the one we expect.
+    pub fn is_consistent(&self) -> bool {
+        let mut diagonal = 0.0;
+        let mut extent: f32 = 0.0;
+        for i in 0..3 {
+            if self.min[i] > self.max[i]
+                || (self.center[i] - (self.min[i] + self.max[i]) / 2.0).abs() > 1e-3
+            {
+                return false;
+            }
+            diagonal += (self.max[i] - self.min[i]).powi(2);
+            extent = extent.max(self.max[i] - self.min[i]);
+        }
+        // A sphere around the box center is somewhere between these two
+        let slack = self.radius * 0.01 + 1e-3;
+        self.radius >= extent / 2.0 - slack && self.radius <= diagonal.sqrt() / 2.0 + slack
+    }
 }
 
 #[binread]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct BufferLayout {
     #[br(temp)]
     len: u16,
@@ -29,7 +92,7 @@ pub struct BufferLayout {
     pub data: String,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum BufferType {
     Position,
     Normal,
@@ -41,14 +104,95 @@ pub enum BufferType {
     Binormal,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How byte normals, tangents and binormals map to -1..1. Nothing in the header says which,
+/// see `DirectionEncoding::detect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum DirectionEncoding {
+    /// Signed bytes, `i8 / 127`
+    #[default]
+    Snorm,
+    /// Unsigned bytes remapped around 128, `u8 / 127.5 - 1`
+    BiasedUnorm,
+}
+
+impl DirectionEncoding {
+    fn decode(&self, value: u8) -> f32 {
+        match self {
+            DirectionEncoding::Snorm => (value as i8 as f32 / 127.0).max(-1.0),
+            DirectionEncoding::BiasedUnorm => value as f32 / 127.5 - 1.0,
+        }
+    }
+
+    /// Picks the encoding that decodes the byte normals (or tangents, binormals) of a
+    /// stream closest to unit length. Real directions are unit vectors either way, read
+    /// with the wrong encoding every component past 127 flips sign and the lengths scatter.
+    pub fn detect(buffers: &[Buffer], stream: &[u8]) -> Self {
+        let stride = buffers.iter().map(Buffer::stride).sum::<u64>() as usize;
+        let mut offset = 0;
+        let mut directions = Vec::new();
+        for buffer in buffers {
+            if buffer.buffer_format == BufferFormat::Byte
+                && buffer.size >= 3
+                && matches!(
+                    buffer.buffer_type,
+                    BufferType::Normal | BufferType::Tangent | BufferType::Binormal
+                )
+            {
+                directions.push(offset);
+            }
+            offset += buffer.stride() as usize;
+        }
+        if stride == 0 || directions.is_empty() {
+            return DirectionEncoding::default();
+        }
+
+        let error = |encoding: DirectionEncoding| -> f32 {
+            stream
+                .chunks_exact(stride)
+                .flat_map(|vertex| directions.iter().map(move |offset| &vertex[*offset..]))
+                .map(|xyz| {
+                    let length = xyz[..3]
+                        .iter()
+                        .map(|c| encoding.decode(*c).powi(2))
+                        .sum::<f32>()
+                        .sqrt();
+                    (length - 1.0).abs()
+                })
+                .sum()
+        };
+        if error(DirectionEncoding::BiasedUnorm) < error(DirectionEncoding::Snorm) {
+            DirectionEncoding::BiasedUnorm
+        } else {
+            DirectionEncoding::Snorm
+        }
+    }
+}
+
+impl BufferType {
+    /// Maps a byte component to a float according to what the attribute holds.
+    pub fn decode_byte(&self, value: u8, directions: DirectionEncoding) -> f32 {
+        match self {
+            BufferType::Normal | BufferType::Tangent | BufferType::Binormal => {
+                directions.decode(value)
+            }
+            // UNORM
+            BufferType::Color | BufferType::Texcoord | BufferType::BlendWeight => {
+                value as f32 / 255.0
+            }
+            // Plain integers
+            BufferType::Position | BufferType::BlendIndices => value as f32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum BufferFormat {
     Float,
     Byte,
     Half,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct Buffer {
     pub buffer_type: BufferType,
     pub size: u8,
@@ -59,7 +203,8 @@ pub struct Buffer {
 pub struct Vertex {
     pub position: [f32; 3],
     pub normal: [f32; 4],
-    pub color: [f32; 4],
+    /// One RGBA entry per colour layer, normalized to 0-1
+    pub colors: Vec<[f32; 4]>,
     pub texcoords: Vec<[f32; 2]>,
     pub blend_weight: [f32; 4],
     pub blend_indices: [u32; 4],
@@ -80,11 +225,8 @@ impl Vertex {
         } else {
             new.normal = other.normal;
         }
-        if self.color != [0.0; 4] {
-            new.color = self.color;
-        } else {
-            new.color = other.color;
-        }
+        // Colour layers can be spread over several streams
+        new.colors = [self.colors.as_slice(), other.colors.as_slice()].concat();
         if !self.texcoords.is_empty() {
             new.texcoords = self.texcoords.clone();
         } else {
@@ -112,107 +254,404 @@ impl Vertex {
         }
         new
     }
+
+    /// Normalizes the tangent frame and stores the bitangent sign in `tangent[3]`.
+    /// The sign comes from `binormal` when present, otherwise from the packed tangent w.
+    pub fn normalize_tangent_frame(&mut self) {
+        self.normal = normalize(self.normal);
+        self.tangent = normalize(self.tangent);
+        self.binormal = normalize(self.binormal);
+        let sign = if self.binormal[0..3] != [0.0; 3] {
+            let n = self.normal;
+            let t = self.tangent;
+            let cross = [
+                n[1] * t[2] - n[2] * t[1],
+                n[2] * t[0] - n[0] * t[2],
+                n[0] * t[1] - n[1] * t[0],
+            ];
+            cross[0] * self.binormal[0] + cross[1] * self.binormal[1] + cross[2] * self.binormal[2]
+        } else {
+            self.tangent[3]
+        };
+        self.tangent[3] = if sign < 0.0 { -1.0 } else { 1.0 };
+    }
+}
+
+/// Normalizes the xyz part of a vector, keeping w as is.
+fn normalize(v: [f32; 4]) -> [f32; 4] {
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if len <= f32::EPSILON {
+        return v;
+    }
+    [v[0] / len, v[1] / len, v[2] / len, v[3]]
+}
+
+/// A range of the index buffer drawn with one material.
+#[derive(Debug, Clone, Serialize)]
+pub struct Section {
+    pub index_start: u32,
+    pub index_count: u32,
+    pub material: Option<String>,
+}
+
+#[binread]
+#[derive(Debug, Clone)]
+struct SectionTable {
+    #[br(temp)]
+    count: u32,
+    #[br(count = count)]
+    sections: Vec<SectionEntry>,
+}
+
+#[binread]
+#[derive(Debug, Clone)]
+struct SectionEntry {
+    index_start: u32,
+    index_count: u32,
+    #[br(temp)]
+    _size: u16,
+    #[br(map = |s: Vec<u8>| String::from_utf8_lossy(&s).to_string(), count = _size)]
+    material: String,
+}
+
+impl SectionTable {
+    /// Whether the entries cover the index buffer back to back in whole triangles, which
+    /// random bytes after the streams practically never do.
+    fn covers(&self, index_count: u32) -> bool {
+        let mut end = 0;
+        for entry in &self.sections {
+            if entry.index_start != end
+                || entry.index_count == 0
+                || entry.index_count / 3 * 3 != entry.index_count
+            {
+                return false;
+            }
+            end = match end.checked_add(entry.index_count) {
+                Some(end) => end,
+                None => return false,
+            };
+        }
+        !self.sections.is_empty() && end == index_count
+    }
+}
+
+/// Sections from the table that may follow the vertex streams, or one section over the
+/// whole index buffer when there is no table covering it.
+// TODO: the trailing table has only been seen on multi-material meshes, confirm layout
+pub fn read_sections<R: Read + Seek>(reader: &mut R, model: &ModelHeader) -> Result<Vec<Section>> {
+    let start = reader.stream_position()?;
+    let table = reader
+        .read_le::<SectionTable>()
+        .ok()
+        .filter(|table| table.covers(model.index_count));
+    match table {
+        Some(table) => Ok(table
+            .sections
+            .into_iter()
+            .map(|entry| Section {
+                index_start: entry.index_start,
+                index_count: entry.index_count,
+                material: (!entry.material.is_empty()).then_some(entry.material),
+            })
+            .collect()),
+        None => {
+            reader.seek(SeekFrom::Start(start))?;
+            Ok(vec![Section {
+                index_start: 0,
+                index_count: model.index_count,
+                material: None,
+            }])
+        }
+    }
 }
 
+// Layouts are `_` separated elements of <semantic><count><format>, e.g.
 // P3F_N4B_T2F_T2F - Posion 3 Float, Normal 4 Byte, Texcoord 2 Float, Texcoord 2 Float
 // T4H_B4H - Tangent 4 Half, Binormal 4 Half
+// `T` is a texcoord when it has 2 components and a tangent when it has 3 or 4.
 
-pub fn get_buffers_from_layout(data: &str) -> Vec<Buffer> {
-    if data == "None" {
-        return Vec::new();
-    }
-    let mut buffers = Vec::new();
-    let a = data.split("_").collect::<Vec<&str>>();
-
-    for b in a {
-        let buffer_type = match b.chars().next().unwrap() {
-            'P' => BufferType::Position,
-            'N' => BufferType::Normal,
-            'C' => BufferType::Color,
-            'T' => BufferType::Texcoord, // and Tangent
-            'W' => BufferType::BlendWeight,
-            'I' => BufferType::BlendIndices,
-            'B' => BufferType::Binormal,
-            _ => panic!("Unknown buffer type: {}", data),
-        };
+pub fn get_buffers_from_layout(data: &str) -> Result<Vec<Buffer>> {
+    if data.is_empty() || data == "None" {
+        return Ok(Vec::new());
+    }
 
-        let size = b.chars().nth(1).unwrap().to_digit(10).unwrap() as u8;
+    data.split('_')
+        .map(|element| parse_buffer(element).with_context(|| format!("in layout {:?}", data)))
+        .collect()
+}
 
-        let buffer_format = match b.chars().nth(2).unwrap() {
-            'F' => BufferFormat::Float,
-            'B' => BufferFormat::Byte,
-            'H' => BufferFormat::Half,
-            _ => panic!("Unknown buffer format: {}", data),
-        };
+fn parse_buffer(element: &str) -> Result<Buffer> {
+    let mut chars = element.chars();
+    let semantic = chars.next().context("empty layout element")?;
+    let rest = chars.as_str();
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (count, format) = rest.split_at(digits);
 
-        buffers.push(Buffer {
-            buffer_type,
+    // Component counts each semantic can be decoded with
+    let counts: &[u8] = match semantic {
+        'P' | 'N' | 'C' | 'B' => &[3, 4],
+        // 2 is a texcoord, 3 or 4 a tangent
+        'T' => &[2, 3, 4],
+        'W' | 'I' => &[1, 2, 3, 4],
+        _ => bail!("unknown buffer type {:?} in {:?}", semantic, element),
+    };
+    let size: u8 = count
+        .parse()
+        .with_context(|| format!("missing component count in {:?}", element))?;
+    if !counts.contains(&size) {
+        bail!(
+            "unsupported component count {} for {:?} in {:?}, expected one of {:?}",
             size,
-            buffer_format,
-        });
+            semantic,
+            element,
+            counts
+        );
     }
 
-    buffers
-}
+    let buffer_type = match (semantic, size) {
+        ('P', _) => BufferType::Position,
+        ('N', _) => BufferType::Normal,
+        ('C', _) => BufferType::Color,
+        ('T', 2) => BufferType::Texcoord,
+        ('T', _) => BufferType::Tangent,
+        ('W', _) => BufferType::BlendWeight,
+        ('I', _) => BufferType::BlendIndices,
+        _ => BufferType::Binormal,
+    };
 
-pub fn export_model(model_path: &str) -> Result<(), Error> {
-    let mut mfile = File::open(model_path)?;
-    let fileheader: MessiahHeader = mfile.read_le()?;
-    println!("{:?}", fileheader);
-    let model = match fileheader.data {
-        MessiahTypes::Model(model) => model,
-        _ => panic!("Not a model"),
+    let buffer_format = match format {
+        "F" => BufferFormat::Float,
+        "B" => BufferFormat::Byte,
+        "H" => BufferFormat::Half,
+        _ => bail!("unknown buffer format {:?} in {:?}", format, element),
     };
-    // println!("{:?}", model);
 
+    Ok(Buffer {
+        buffer_type,
+        size,
+        buffer_format,
+    })
+}
+
+/// Index, vertex and section data of one LOD.
+#[derive(Debug, Clone)]
+pub struct MeshData {
+    pub indices: Vec<Vec<u32>>,
+    pub vertices: Vec<Vertex>,
+    pub sections: Vec<Section>,
+}
+
+impl BufferFormat {
+    /// Size of one component in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            BufferFormat::Float => 4,
+            BufferFormat::Byte => 1,
+            BufferFormat::Half => 2,
+        }
+    }
+}
+
+impl Buffer {
+    /// Size of this attribute for one vertex in bytes.
+    pub fn stride(&self) -> u64 {
+        self.size as u64 * self.buffer_format.size()
+    }
+
+    /// Reads and decodes one component of this attribute.
+    fn read_component<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        directions: DirectionEncoding,
+    ) -> Result<f32> {
+        Ok(match self.buffer_format {
+            BufferFormat::Float => reader.read_le::<f32>()?,
+            BufferFormat::Byte => self
+                .buffer_type
+                .decode_byte(reader.read_le::<u8>()?, directions),
+            BufferFormat::Half => f16::from_bits(reader.read_le::<u16>()?).to_f32(),
+        })
+    }
+}
+
+/// Reads one vertex stream laid out as `buffers`, with the encoding of its byte directions.
+fn read_stream<R: Read + Seek>(
+    reader: &mut R,
+    buffers: &[Buffer],
+    vertex_count: u32,
+) -> Result<(Cursor<Vec<u8>>, DirectionEncoding)> {
+    let stride: u64 = buffers.iter().map(Buffer::stride).sum();
+    let mut stream = vec![0; (stride * vertex_count as u64) as usize];
+    reader.read_exact(&mut stream)?;
+    let directions = DirectionEncoding::detect(buffers, &stream);
+    Ok((Cursor::new(stream), directions))
+}
+
+/// Size of the vertex streams that follow the indices.
+fn vertex_stream_size(model: &ModelHeader) -> Result<u64> {
+    let mut size = 0;
+    for layout in &model.buffer_layouts {
+        let stride: u64 = get_buffers_from_layout(&layout.data)?
+            .iter()
+            .map(Buffer::stride)
+            .sum();
+        size += stride * model.vertex_count as u64;
+    }
+    Ok(size)
+}
+
+/// Works out whether the index stream at the current position is 16 or 32 bit.
+/// The header has no flag for it, so this goes by which one fits the file and
+/// whether the data reads as valid 32-bit indices. Leaves the reader where it was.
+fn detect_index_stride<R: Read + Seek>(
+    reader: &mut R,
+    model: &ModelHeader,
+    file_size: u64,
+) -> Result<u64> {
+    let start = reader.stream_position()?;
+    let remaining = file_size.saturating_sub(start);
+    let index_count = model.index_count as u64;
+    let vertex_size = vertex_stream_size(model)?;
+
+    let fits = |stride: u64| index_count * stride + vertex_size <= remaining;
+    if !fits(2) {
+        bail!(
+            "model is truncated: {} indices and {} vertices need at least {:#x} bytes at {:#x}, but only {:#x} remain",
+            model.index_count,
+            model.vertex_count,
+            index_count * 2 + vertex_size,
+            start,
+            remaining
+        );
+    }
+    if model.vertex_count > 0xFFFF {
+        if !fits(4) {
+            bail!(
+                "model is truncated: {} vertices need 32-bit indices, {:#x} bytes at {:#x}, but only {:#x} remain",
+                model.vertex_count,
+                index_count * 4 + vertex_size,
+                start,
+                remaining
+            );
+        }
+        return Ok(4);
+    }
+    if !fits(4) {
+        return Ok(2);
+    }
+    if index_count * 4 + vertex_size == remaining {
+        return Ok(4);
+    }
+    if index_count * 2 + vertex_size == remaining {
+        return Ok(2);
+    }
+
+    // Pairs of 16-bit indices read as one u32 are almost never below the vertex count
+    let mut data = vec![0; index_count as usize * 4];
+    reader.read_exact(&mut data)?;
+    reader.seek(SeekFrom::Start(start))?;
+    let is_32bit = data
+        .chunks_exact(4)
+        .all(|i| u32::from_le_bytes(i.try_into().unwrap()) < model.vertex_count);
+    Ok(if is_32bit { 4 } else { 2 })
+}
+
+/// Reads the header of the next LOD at the current position, `None` at the end of the file
+/// or when what follows the streams of `previous` doesn't look like one.
+// TODO: only seen on a few mobile packages, LOD blocks appear to repeat the model header as is
+pub(crate) fn read_lod_header<R: Read + Seek>(
+    reader: &mut R,
+    previous: &ModelHeader,
+    file_size: u64,
+) -> Result<Option<ModelHeader>> {
+    let start = reader.stream_position()?;
+    if start >= file_size {
+        return Ok(None);
+    }
+    let header = reader.read_le::<ModelHeader>().ok().filter(|header| {
+        header.vertex_count > 0
+            && header.index_count > 0
+            && header.index_count / 3 * 3 == header.index_count
+            && header
+                .buffer_layouts
+                .iter()
+                .map(|layout| &layout.data)
+                .eq(previous.buffer_layouts.iter().map(|layout| &layout.data))
+            && reader.stream_position().is_ok_and(|pos| {
+                vertex_stream_size(header)
+                    .is_ok_and(|size| pos + header.index_count as u64 * 2 + size <= file_size)
+            })
+    });
+    if header.is_none() {
+        println!(
+            "{:#x} bytes at {:#x} after the streams don't look like a LOD block, stopping at the \
+             LODs read so far",
+            file_size - start,
+            start
+        );
+        reader.seek(SeekFrom::Start(start))?;
+    }
+    Ok(header)
+}
+
+pub fn read_mesh_data<R: Read + Seek>(
+    reader: &mut R,
+    model: &ModelHeader,
+    file_size: u64,
+) -> Result<MeshData> {
+    if model.index_count / 3 * 3 != model.index_count {
+        bail!(
+            "index count {} is not a multiple of 3, wrong header layout?",
+            model.index_count
+        );
+    }
+    println!("{:?}", model.bounds);
+    if !model.bounds.is_consistent() {
+        println!("Header bounds don't look like a bounding box, header layout may have changed");
+    }
+
+    let index_stride = detect_index_stride(reader, model, file_size)?;
+    println!("{}-bit indices", index_stride * 8);
     let mut indices: Vec<Vec<u32>> = Vec::new();
-    let index_stride = if model.vertex_count > 0xFFFF { 4 } else { 2 };
     for _ in 0..model.index_count / 3 {
         let mut index = Vec::new();
         for _ in 0..3 {
-            match index_stride {
-                2 => {
-                    index.push(mfile.read_le::<u16>()? as u32);
-                }
-                4 => {
-                    index.push(mfile.read_le::<u32>()?);
-                }
-                _ => {
-                    unimplemented!();
-                }
+            let value = match index_stride {
+                2 => reader.read_le::<u16>()? as u32,
+                _ => reader.read_le::<u32>()?,
+            };
+            if value >= model.vertex_count {
+                bail!(
+                    "index {} of triangle {} is out of range for {} vertices",
+                    value,
+                    indices.len(),
+                    model.vertex_count
+                );
             }
+            index.push(value);
         }
         indices.push(index);
     }
 
     let mut vertices: Vec<Vertex> = vec![Vertex::default(); model.vertex_count as usize];
     for layout in &model.buffer_layouts {
-        let buffer = get_buffers_from_layout(&layout.data);
+        let buffer = get_buffers_from_layout(&layout.data)?;
         if buffer.is_empty() {
             continue;
         }
 
-        let t_is_tangent = !(buffer.iter().any(|b| b.buffer_type == BufferType::Texcoord)
-            && buffer.iter().any(|b| b.buffer_type == BufferType::Position));
-
         println!("{:?}", buffer);
+        let (mut stream, directions) = read_stream(reader, &buffer, model.vertex_count)?;
         for vid in 0..model.vertex_count {
             let mut vertex = Vertex::default();
             for b in &buffer {
-                let mut data = Vec::new();
+                // Components missing from the layout stay zero
+                let mut data = [0.0; 4];
                 //println!("{:?}", b.buffer_type);
-                for _ in 0..b.size {
-                    match b.buffer_format {
-                        BufferFormat::Float => {
-                            data.push(mfile.read_le::<f32>()?);
-                        }
-                        BufferFormat::Byte => {
-                            data.push(mfile.read_le::<u8>()? as f32);
-                        }
-                        BufferFormat::Half => {
-                            data.push(mfile.read_le::<u16>()? as f32 / 65535.0);
-                        }
-                    }
+                for component in data.iter_mut().take(b.size as usize) {
+                    *component = b.read_component(&mut stream, directions)?;
                     //println!("{:?}", data);
                 }
                 match b.buffer_type {
@@ -223,14 +662,14 @@ pub fn export_model(model_path: &str) -> Result<(), Error> {
                         vertex.normal = [data[0], data[1], data[2], data[3]];
                     }
                     BufferType::Color => {
-                        vertex.color = [data[0], data[1], data[2], data[3]];
+                        // RGB streams are opaque
+                        if b.size < 4 {
+                            data[3] = 1.0;
+                        }
+                        vertex.colors.push(data.map(|c| c.clamp(0.0, 1.0)));
                     }
                     BufferType::Texcoord => {
-                        if t_is_tangent {
-                            vertex.tangent = [data[0], data[1], data[2], data[3]];
-                        } else {
-                            vertex.texcoords.push([data[0], data[1]]);
-                        }
+                        vertex.texcoords.push([data[0], data[1]]);
                     }
                     BufferType::BlendWeight => {
                         vertex.blend_weight = [data[0], data[1], data[2], data[3]];
@@ -254,25 +693,424 @@ pub fn export_model(model_path: &str) -> Result<(), Error> {
             vertices[vid as usize] = vertices[vid as usize].combine(&vertex);
         }
     }
+    vertices
+        .iter_mut()
+        .for_each(|vertex| vertex.normalize_tangent_frame());
+    let outside = vertices
+        .iter()
+        .filter(|vertex| !model.bounds.contains(vertex.position))
+        .count();
+    if outside > 0 {
+        println!(
+            "{} of {} vertices are outside the header bounds, positions may be decoded wrong",
+            outside,
+            vertices.len()
+        );
+    }
     println!("{:?}", vertices.len());
     println!("{:?}", vertices.first());
     println!("{:?}", vertices.last());
 
+    let sections = read_sections(reader, model)?;
+    println!("{:?}", sections);
+
+    Ok(MeshData {
+        indices,
+        vertices,
+        sections,
+    })
+}
+
+#[derive(clap::ValueEnum, PartialEq, Debug, Clone, Copy, Default)]
+pub enum ModelFormat {
+    #[default]
+    #[value(name = "cast")]
+    Cast,
+    #[value(name = "glb")]
+    Glb,
+}
+
+impl ModelFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ModelFormat::Cast => "cast",
+            ModelFormat::Glb => "glb",
+        }
+    }
+}
+
+/// Options for converting a model.
+#[derive(Debug, Clone, Default)]
+pub struct ModelExportOptions {
+    pub lod0_only: bool,
+    pub format: ModelFormat,
+    /// Extracted tree to look up materials and textures in
+    pub resource_root: Option<PathBuf>,
+}
+
+/// Converts textures used by batch converted models into each asset's directory.
+pub struct TextureOutput<'a> {
+    pub version: &'a Version,
+    pub normal_convention: NormalConvention,
+}
+
+pub fn export_model(model_path: &str, options: &ModelExportOptions) -> Result<(), Error> {
+    if file::read_type_id(Path::new(model_path)) == Some(file::MATERIAL_ID) {
+        return material::export_material(model_path);
+    }
+    let library = options
+        .resource_root
+        .as_deref()
+        .map(MaterialLibrary::load)
+        .transpose()?;
+    convert_model(
+        Path::new(model_path),
+        Path::new(model_path),
+        options,
+        library.as_ref(),
+        None,
+    )
+}
+
+/// Converts every model under `input_path` into its own directory under `output_root`,
+/// e.g. `char/hero/body.model` ends up as `char/hero/body/body.cast` with its textures
+/// in `char/hero/body/textures`. Extract with `-p` first so references resolve by name.
+pub fn export_model_tree(
+    input_path: &Path,
+    output_root: &Path,
+    options: &ModelExportOptions,
+    textures: &TextureOutput,
+) -> Result<()> {
+    let models = file::walk_files(input_path)?
+        .into_par_iter()
+        .filter(|path| file::read_type_id(path) == Some(file::MODEL_ID))
+        .collect::<Vec<_>>();
+    println!("Found {} models", models.len());
+
+    let resource_root = options.resource_root.as_deref().unwrap_or(input_path);
+    let library = MaterialLibrary::load(resource_root)?;
+
+    let results = models
+        .par_iter()
+        .map(|path| {
+            let relative = path.strip_prefix(input_path).unwrap_or(path);
+            let asset_dir = output_root.join(relative.with_extension(""));
+            let output_path = asset_dir.join(relative.file_name().unwrap_or_default());
+            let result = std::fs::create_dir_all(&asset_dir)
+                .map_err(Error::from)
+                .and_then(|_| {
+                    convert_model(path, &output_path, options, Some(&library), Some(textures))
+                });
+            (path, result)
+        })
+        .collect::<Vec<_>>();
+
+    let failed = results
+        .iter()
+        .filter_map(|(path, result)| result.as_ref().err().map(|e| (path, e)))
+        .inspect(|(path, e)| println!("Failed {:?}: {:#}", path, e))
+        .count();
+    println!("{} models, {} failed", results.len(), failed);
+    Ok(())
+}
+
+/// Converts textures of `resolved` next to `output_path` and points it at the new images.
+fn localize_textures(
+    resolved: &mut ResolvedMaterial,
+    library: &MaterialLibrary,
+    output_path: &Path,
+    output: &TextureOutput,
+) -> Result<()> {
+    let texture_dir = output_path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join("textures");
+    std::fs::create_dir_all(&texture_dir)?;
+    let mut missing = Vec::new();
+    for (slot, image) in resolved.textures.iter_mut() {
+        let Some(source) = resolved
+            .material
+            .textures
+            .get(slot)
+            .and_then(|reference| library.find_texture(reference))
+        else {
+            continue;
+        };
+        let texture_path = texture_dir.join(source.file_name().unwrap_or_default());
+        let local_image = texture::read_header(source).and_then(|header| {
+            if let Some(local_image) = texture::preview_image(&texture_path, &header) {
+                return Ok(local_image);
+            }
+            texture::convert_texture(
+                output.version,
+                source,
+                &texture_path,
+                output.normal_convention,
+            )?;
+            texture::preview_image(&texture_path, &header).context("no image was written")
+        });
+        match local_image {
+            Ok(local_image) => *image = local_image,
+            // Not every referenced texture converts, leave the slot out rather than point at
+            // an image that was never written
+            Err(e) => {
+                println!(
+                    "Dropping {} texture, converting {:?} failed: {:#}",
+                    slot, source, e
+                );
+                missing.push(slot.clone());
+            }
+        }
+    }
+    resolved
+        .textures
+        .retain(|(slot, _)| !missing.contains(slot));
+    Ok(())
+}
+
+/// Converts one model file, writing `output_path` with the format's extension and
+/// `lod{n}` files for further LODs.
+fn convert_model(
+    model_path: &Path,
+    output_path: &Path,
+    options: &ModelExportOptions,
+    library: Option<&MaterialLibrary>,
+    textures: Option<&TextureOutput>,
+) -> Result<(), Error> {
+    let mut mfile = File::open(model_path)?;
+    let file_size = mfile.metadata()?.len();
+    let fileheader: MessiahHeader = mfile.read_le()?;
+    let model = match fileheader.data {
+        MessiahTypes::Model(model) => model,
+        MessiahTypes::Unknown { id, bytes } => bail!(
+            "{:?} is not a model (unknown type {:#x}, {} bytes)",
+            model_path,
+            id,
+            bytes.len()
+        ),
+        data => bail!(
+            "{:?} is not a model (type {:#x}, {})",
+            model_path,
+            data.id(),
+            data.name()
+        ),
+    };
+
+    let mut lods = vec![read_mesh_data(&mut mfile, &model, file_size)?];
+    if !options.lod0_only {
+        while let Some(lod) = read_lod_header(&mut mfile, &model, file_size)? {
+            lods.push(read_mesh_data(&mut mfile, &lod, file_size)?);
+        }
+    }
+    println!("{} LOD(s)", lods.len());
+
+    let materials = match library {
+        Some(library) => resolve_materials(
+            lods.iter().flat_map(|lod| &lod.sections),
+            library,
+            output_path,
+            textures,
+        )?,
+        None => HashMap::new(),
+    };
+
+    let extension = options.format.extension();
+    for (i, lod) in lods.iter().enumerate() {
+        let output_path = if i == 0 {
+            output_path.with_extension(extension)
+        } else {
+            output_path.with_extension(format!("lod{}.{}", i, extension))
+        };
+        write_mesh_data(&output_path, lod, &materials, options.format)?;
+    }
+    Ok(())
+}
+
+/// Looks up the materials used by `sections`, converting their textures next to
+/// `output_path` when `textures` is set.
+pub fn resolve_materials<'a>(
+    sections: impl IntoIterator<Item = &'a Section>,
+    library: &MaterialLibrary,
+    output_path: &Path,
+    textures: Option<&TextureOutput>,
+) -> Result<HashMap<String, ResolvedMaterial>> {
+    let mut materials: HashMap<String, ResolvedMaterial> = HashMap::new();
+    for name in sections.into_iter().filter_map(|s| s.material.as_ref()) {
+        if materials.contains_key(name) {
+            continue;
+        }
+        match library.resolve(name) {
+            Some(mut resolved) => {
+                if let Some(textures) = textures {
+                    localize_textures(&mut resolved, library, output_path, textures)?;
+                }
+                materials.insert(name.clone(), resolved);
+            }
+            None => println!("Material {} not found", name),
+        }
+    }
+    Ok(materials)
+}
+
+/// Writes one LOD in `format` to `output_path`.
+pub fn write_mesh_data(
+    output_path: &Path,
+    mesh_data: &MeshData,
+    materials: &HashMap<String, ResolvedMaterial>,
+    format: ModelFormat,
+) -> Result<()> {
+    match format {
+        ModelFormat::Cast => write_cast_model(output_path, mesh_data, materials),
+        ModelFormat::Glb => {
+            let output_dir = output_path.parent().unwrap_or(Path::new(""));
+            let textures = materials
+                .iter()
+                .map(|(name, resolved)| {
+                    let mut textures = gltf::MaterialTextures {
+                        extras: serde_json::to_value(&resolved.material).unwrap_or_default(),
+                        ..Default::default()
+                    };
+                    for (slot, path) in &resolved.textures {
+                        let path = file::relative_path(output_dir, path);
+                        match material::cast_slot(slot) {
+                            Some("albedo") => textures.base_color = Some(path),
+                            Some("normal") => textures.normal = Some(path),
+                            _ => {}
+                        }
+                    }
+                    (name.clone(), textures)
+                })
+                .collect();
+            gltf::write_glb(output_path, mesh_data, &textures)
+        }
+    }
+}
+
+fn write_cast_model(
+    output_path: &Path,
+    mesh_data: &MeshData,
+    resolved_materials: &HashMap<String, ResolvedMaterial>,
+) -> Result<()> {
+    let MeshData {
+        indices,
+        vertices,
+        sections,
+    } = mesh_data;
+
+    let mut castfile = CastFile::new();
+    let mut root = CastNode::root();
+    let model_node = root.create(CastId::Model);
+
+    let mut materials: HashMap<String, u64> = HashMap::new();
+    for material in sections.iter().filter_map(|s| s.material.as_ref()) {
+        if materials.contains_key(material) {
+            continue;
+        }
+        let cast_material = model_node.create(CastId::Material);
+        cast_material
+            .create_property(CastPropertyId::String, "n")
+            .push(material.clone());
+        cast_material
+            .create_property(CastPropertyId::String, "t")
+            .push("pbr".to_string());
+        if let Some(resolved) = resolved_materials.get(material) {
+            let output_dir = output_path.parent().unwrap_or(Path::new(""));
+            let mut extra = 0;
+            for (slot, path) in &resolved.textures {
+                let file_hash = {
+                    let cast_file = cast_material.create(CastId::File);
+                    cast_file.create_property(CastPropertyId::String, "p").push(
+                        file::relative_path(output_dir, path)
+                            .to_string_lossy()
+                            .to_string(),
+                    );
+                    cast_file.hash()
+                };
+                let cast_slot = match material::cast_slot(slot) {
+                    Some(cast_slot) => cast_slot.to_string(),
+                    None => {
+                        extra += 1;
+                        format!("extra{}", extra - 1)
+                    }
+                };
+                cast_material
+                    .create_property(CastPropertyId::Integer64, cast_slot)
+                    .push(file_hash);
+            }
+        }
+        materials.insert(material.clone(), cast_material.hash());
+    }
+
+    for section in sections {
+        let start = (section.index_start / 3) as usize;
+        let end = start + (section.index_count / 3) as usize;
+        let material = section.material.as_ref().map(|m| materials[m]);
+        let faces = indices.get(start..end).with_context(|| {
+            format!(
+                "section {:?} is outside the {} triangles",
+                section,
+                indices.len()
+            )
+        })?;
+        write_cast_mesh(model_node, vertices, faces, material)?;
+    }
+
+    castfile.push(root);
+
+    let mut cast_out = File::create(output_path)?;
+    castfile.write(&mut cast_out)?;
+    Ok(())
+}
+
+/// Writes one Cast mesh holding only the vertices referenced by `faces`.
+fn write_cast_mesh(
+    model_node: &mut CastNode,
+    vertices: &[Vertex],
+    faces: &[Vec<u32>],
+    material: Option<u64>,
+) -> Result<()> {
+    let mut remap: HashMap<u32, u32> = HashMap::new();
+    let mut used: Vec<&Vertex> = Vec::new();
+    let faces = faces
+        .iter()
+        .map(|face| {
+            face.iter()
+                .map(|i| {
+                    if let Some(remapped) = remap.get(i) {
+                        return Ok(*remapped);
+                    }
+                    let vertex = vertices.get(*i as usize).with_context(|| {
+                        format!(
+                            "index {} is out of range for {} vertices",
+                            i,
+                            vertices.len()
+                        )
+                    })?;
+                    used.push(vertex);
+                    remap.insert(*i, used.len() as u32 - 1);
+                    Ok(used.len() as u32 - 1)
+                })
+                .collect::<Result<Vec<u32>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let vertices = used;
+
     let uv_layer_count: u32 = vertices
         .iter()
         .map(|v| v.texcoords.len())
         .max()
         .unwrap_or(0) as u32;
 
-    let mut castfile = CastFile::new();
-    let mut root = CastNode::root();
-    let mesh = root.create(CastId::Model).create(CastId::Mesh);
+    let mesh = model_node.create(CastId::Mesh);
 
     let uvlayers = mesh.create_property(CastPropertyId::Integer32, "ul");
     uvlayers.push(uv_layer_count);
 
-    let colorlayers = mesh.create_property(CastPropertyId::Integer32, "cl");
-    colorlayers.push(1_u32);
+    let color_layer_count: u32 = vertices.iter().map(|v| v.colors.len()).max().unwrap_or(0) as u32;
+    if color_layer_count > 0 {
+        let colorlayers = mesh.create_property(CastPropertyId::Integer32, "cl");
+        colorlayers.push(color_layer_count);
+    }
 
     let pos = mesh.create_property(CastPropertyId::Vector3, "vp");
     for vertex in &vertices {
@@ -283,22 +1121,27 @@ pub fn export_model(model_path: &str) -> Result<(), Error> {
         ));
     }
 
-    // let norm = mesh.create_property(CastPropertyId::Vector3, "vn");
-    // for vertex in &vertices {
-    //     norm.push(Vector3::new(
-    //         vertex.normal[0],
-    //         vertex.normal[1],
-    //         vertex.normal[2],
-    //     ));
-    // }
+    if vertices.iter().any(|v| v.normal[0..3] != [0.0; 3]) {
+        let norm = mesh.create_property(CastPropertyId::Vector3, "vn");
+        for vertex in &vertices {
+            norm.push(Vector3::new(
+                vertex.normal[0],
+                vertex.normal[1],
+                vertex.normal[2],
+            ));
+        }
+    }
 
-    let color = mesh.create_property(CastPropertyId::Integer32, "c0");
-    for vertex in &vertices {
-        let col: u32 = (((vertex.color[0] * 255.0) as u32) << 24)
-            | (((vertex.color[1] * 255.0) as u32) << 16)
-            | (((vertex.color[2] * 255.0) as u32) << 8)
-            | ((vertex.color[3] * 255.0) as u32);
-        color.push(col);
+    for i in 0..color_layer_count {
+        let color = mesh.create_property(CastPropertyId::Integer32, format!("c{}", i));
+        for vertex in &vertices {
+            // Vertices without this layer are left white
+            let [r, g, b, a] = vertex
+                .colors
+                .get(i as usize)
+                .map_or([255; 4], |c| c.map(|c| (c * 255.0).round() as u32));
+            color.push((r << 24) | (g << 16) | (b << 8) | a);
+        }
     }
 
     for i in 0..uv_layer_count {
@@ -331,23 +1174,480 @@ pub fn export_model(model_path: &str) -> Result<(), Error> {
         bindices.push(vertex.blend_indices[3]);
     }
 
-    // let tangent = mesh.create_property(CastPropertyId::Vector3, "vt");
-    // for vertex in &vertices {
-    //     tangent.push(Vector3::new(
-    //         vertex.tangent[0],
-    //         vertex.tangent[1],
-    //         vertex.tangent[2],
-    //     ));
-    // }
+    // Cast tangents have no w, so the bitangent sign of mirrored UVs is lost (glb keeps it)
+    if vertices.iter().any(|v| v.tangent[0..3] != [0.0; 3]) {
+        let tangent = mesh.create_property(CastPropertyId::Vector3, "vt");
+        for vertex in &vertices {
+            tangent.push(Vector3::new(
+                vertex.tangent[0],
+                vertex.tangent[1],
+                vertex.tangent[2],
+            ));
+        }
+    }
 
     let idx = mesh.create_property(CastPropertyId::Integer32, "f");
-    indices.iter().flatten().for_each(|i| {
+    faces.iter().flatten().for_each(|i| {
         idx.push(*i);
     });
 
-    castfile.push(root);
-
-    let mut cast_out = File::create(PathBuf::from(model_path).with_extension("cast"))?;
-    castfile.write(&mut cast_out)?;
+    if let Some(material) = material {
+        let material_ref = mesh.create_property(CastPropertyId::Integer64, "m");
+        material_ref.push(material);
+    }
     Ok(())
 }
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct ComponentStats {
+    min: f32,
+    max: f32,
+    nan: usize,
+    infinite: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct AttributeStats {
+    #[serde(flatten)]
+    buffer: Buffer,
+    offset: u64,
+    components: Vec<ComponentStats>,
+}
+
+/// Statistics of one LOD's streams. Stops at the first problem and reports it as "error".
+pub(crate) fn inspect_lod<R: Read + Seek>(
+    reader: &mut R,
+    model: &ModelHeader,
+    file_size: u64,
+) -> (Value, Result<()>) {
+    let mut lod = json!({ "header": model });
+    let result = (|| -> Result<()> {
+        let index_stride = detect_index_stride(reader, model, file_size)?;
+        let mut indices = Vec::with_capacity(model.index_count as usize);
+        lod["indices"] = json!({
+            "offset": reader.stream_position()?,
+            "stride": index_stride,
+        });
+        for _ in 0..model.index_count {
+            indices.push(match index_stride {
+                2 => reader.read_le::<u16>()? as u32,
+                _ => reader.read_le::<u32>()?,
+            });
+        }
+        let mut referenced = vec![false; model.vertex_count as usize];
+        for index in &indices {
+            if let Some(r) = referenced.get_mut(*index as usize) {
+                *r = true;
+            }
+        }
+        let indices_json = &mut lod["indices"];
+        indices_json["count"] = json!(indices.len());
+        indices_json["min"] = json!(indices.iter().min());
+        indices_json["max"] = json!(indices.iter().max());
+        indices_json["out_of_range"] =
+            json!(indices.iter().filter(|i| **i >= model.vertex_count).count());
+        indices_json["degenerate_triangles"] = json!(indices
+            .chunks_exact(3)
+            .filter(|t| t[0] == t[1] || t[1] == t[2] || t[0] == t[2])
+            .count());
+        indices_json["unreferenced_vertices"] = json!(referenced.iter().filter(|r| !**r).count());
+
+        let mut layouts = Vec::new();
+        for layout in &model.buffer_layouts {
+            let buffers = get_buffers_from_layout(&layout.data)?;
+            let mut attributes = Vec::new();
+            let mut offset = 0;
+            for buffer in &buffers {
+                attributes.push(AttributeStats {
+                    buffer: *buffer,
+                    offset,
+                    components: vec![
+                        ComponentStats {
+                            min: f32::INFINITY,
+                            max: f32::NEG_INFINITY,
+                            nan: 0,
+                            infinite: 0,
+                        };
+                        buffer.size as usize
+                    ],
+                });
+                offset += buffer.stride();
+            }
+            let stream_offset = reader.stream_position()?;
+            let mut directions = None;
+            if !buffers.is_empty() {
+                let (mut stream, encoding) = read_stream(reader, &buffers, model.vertex_count)?;
+                directions = Some(encoding);
+                for _ in 0..model.vertex_count {
+                    for attribute in &mut attributes {
+                        for stats in &mut attribute.components {
+                            let value = attribute.buffer.read_component(&mut stream, encoding)?;
+                            if value.is_nan() {
+                                stats.nan += 1;
+                            } else if value.is_infinite() {
+                                stats.infinite += 1;
+                            } else {
+                                stats.min = stats.min.min(value);
+                                stats.max = stats.max.max(value);
+                            }
+                        }
+                    }
+                }
+            }
+            layouts.push(json!({
+                "layout": layout.data,
+                "offset": stream_offset,
+                "stride": offset,
+                "byte_directions": directions,
+                "attributes": attributes,
+            }));
+        }
+        lod["layouts"] = json!(layouts);
+        lod["sections"] = json!(read_sections(reader, model)?);
+        lod["end"] = json!(reader.stream_position()?);
+        Ok(())
+    })();
+    (lod, result)
+}
+
+/// Describes the header and streams of a model file as JSON, for checking layouts
+/// after format changes.
+pub fn inspect_model(model_path: &Path) -> Result<Value> {
+    let mut mfile = File::open(model_path)?;
+    let file_size = mfile.metadata()?.len();
+    let fileheader: MessiahHeader = mfile.read_le()?;
+    let MessiahTypes::Model(model) = fileheader.data else {
+        bail!("{:?} is not a model", model_path);
+    };
+
+    let mut lods = Vec::new();
+    let mut error = None;
+    let mut header = Some(model);
+    while let Some(model) = header {
+        let (lod, result) = inspect_lod(&mut mfile, &model, file_size);
+        lods.push(lod);
+        if let Err(e) = result {
+            error = Some(format!("{:#}", e));
+            break;
+        }
+        header = match read_lod_header(&mut mfile, &model, file_size) {
+            Ok(header) => header,
+            Err(e) => {
+                error = Some(format!("{:#}", e));
+                None
+            }
+        };
+    }
+
+    let end = mfile.stream_position()?;
+    Ok(json!({
+        "path": model_path,
+        "file_size": file_size,
+        "lods": lods,
+        "error": error,
+        "trailing_bytes": {
+            "offset": end,
+            "size": file_size.saturating_sub(end),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Described = (BufferType, u8, BufferFormat);
+
+    fn describe(layout: &str) -> Vec<Described> {
+        get_buffers_from_layout(layout)
+            .unwrap()
+            .into_iter()
+            .map(|b| (b.buffer_type, b.size, b.buffer_format))
+            .collect()
+    }
+
+    // The example layouts documented on get_buffers_from_layout, not a full dump, add new
+    // ones here as `-i` turns them up
+    #[test]
+    fn known_layouts() {
+        use BufferFormat::*;
+        use BufferType::*;
+        let layouts: &[(&str, &[Described])] = &[
+            ("None", &[]),
+            (
+                "P3F_N4B_T2F_T2F",
+                &[
+                    (Position, 3, Float),
+                    (Normal, 4, Byte),
+                    (Texcoord, 2, Float),
+                    (Texcoord, 2, Float),
+                ],
+            ),
+            ("T4H_B4H", &[(Tangent, 4, Half), (Binormal, 4, Half)]),
+        ];
+        for (layout, expected) in layouts {
+            assert_eq!(describe(layout), *expected, "{}", layout);
+        }
+    }
+
+    #[test]
+    fn every_semantic_and_format() {
+        use BufferFormat::*;
+        use BufferType::*;
+        assert_eq!(
+            describe("P3H_N3F_C4B_T3B_W4B_I4B_B3F"),
+            [
+                (Position, 3, Half),
+                (Normal, 3, Float),
+                (Color, 4, Byte),
+                (Tangent, 3, Byte),
+                (BlendWeight, 4, Byte),
+                (BlendIndices, 4, Byte),
+                (Binormal, 3, Float),
+            ]
+        );
+    }
+
+    fn header(section: [u32; 4]) -> ModelHeader {
+        let [index_start, index_count, vertex_start, vertex_count] = section;
+        ModelHeader {
+            _unk0: 0,
+            _unk4: 0,
+            vertex_count: 8,
+            index_count: 12,
+            buffer_layouts: Vec::new(),
+            bounds: Bounds {
+                min: [0.0; 3],
+                max: [0.0; 3],
+                center: [0.0; 3],
+                radius: 0.0,
+            },
+            section: SectionRange {
+                index_start,
+                index_count,
+                vertex_start,
+                vertex_count,
+            },
+        }
+    }
+
+    fn ranges(sections: &[Section]) -> Vec<(u32, u32)> {
+        sections
+            .iter()
+            .map(|s| (s.index_start, s.index_count))
+            .collect()
+    }
+
+    #[test]
+    fn one_section_without_a_table() {
+        let mut reader = std::io::Cursor::new(vec![0xff; 8]);
+        let sections = read_sections(&mut reader, &header([3, 6, 0, 8])).unwrap();
+        assert_eq!(ranges(&sections), [(0, 12)]);
+        assert_eq!(sections[0].material, None);
+        assert_eq!(reader.position(), 0);
+    }
+
+    #[test]
+    fn section_table() {
+        let table = |entries: &[(u32, u32, &str)]| {
+            let mut data = (entries.len() as u32).to_le_bytes().to_vec();
+            for (start, count, name) in entries {
+                data.extend_from_slice(&start.to_le_bytes());
+                data.extend_from_slice(&count.to_le_bytes());
+                data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                data.extend_from_slice(name.as_bytes());
+            }
+            std::io::Cursor::new(data)
+        };
+        // The header range only covers the first of three materials
+        let model = header([0, 3, 0, 8]);
+
+        let mut reader = table(&[(0, 3, "body"), (3, 6, "glass"), (9, 3, "")]);
+        let sections = read_sections(&mut reader, &model).unwrap();
+        assert_eq!(ranges(&sections), [(0, 3), (3, 6), (9, 3)]);
+        let materials = sections.iter().map(|s| s.material.as_deref());
+        assert!(materials.eq([Some("body"), Some("glass"), None]));
+        assert_eq!(reader.position(), reader.get_ref().len() as u64);
+
+        // Gaps, overlaps, partial triangles and short tables aren't a section table
+        for entries in [
+            &[(0, 3, "a"), (6, 6, "b")][..],
+            &[(0, 6, "a"), (3, 9, "b")],
+            &[(0, 4, "a"), (4, 8, "b")],
+            &[(0, 6, "a")],
+            &[],
+        ] {
+            let mut reader = table(entries);
+            let sections = read_sections(&mut reader, &model).unwrap();
+            assert_eq!(ranges(&sections), [(0, 12)], "{:?}", entries);
+            assert_eq!(reader.position(), 0);
+        }
+    }
+
+    #[test]
+    fn lod_blocks() {
+        let mut previous = header([0, 12, 0, 8]);
+        previous.buffer_layouts = ["P3F", "None", "None", "None"]
+            .map(|data| BufferLayout {
+                data: data.to_string(),
+            })
+            .to_vec();
+        let lod = |layout: &str| {
+            let mut data = Vec::new();
+            for word in [0u32, 0, 4, 6] {
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+            for layout in [layout, "None", "None", "None"] {
+                data.extend_from_slice(&(layout.len() as u16).to_le_bytes());
+                data.extend_from_slice(layout.as_bytes());
+            }
+            data.extend_from_slice(&[0; 14 * 4]);
+            // 16-bit indices and 12 byte positions
+            data.extend_from_slice(&[0; 6 * 2 + 4 * 12]);
+            data
+        };
+
+        let data = lod("P3F");
+        let size = data.len() as u64;
+        let mut reader = std::io::Cursor::new(data);
+        let header = read_lod_header(&mut reader, &previous, size).unwrap();
+        assert_eq!(header.map(|h| h.index_count), Some(6));
+        reader.set_position(size);
+        assert!(read_lod_header(&mut reader, &previous, size)
+            .unwrap()
+            .is_none());
+
+        // Different layouts, or something that isn't a header at all
+        for data in [lod("P3H"), vec![0xff; 16]] {
+            let size = data.len() as u64;
+            let mut reader = std::io::Cursor::new(data);
+            let header = read_lod_header(&mut reader, &previous, size).unwrap();
+            assert!(header.is_none());
+            assert_eq!(reader.position(), 0);
+        }
+    }
+
+    #[test]
+    fn bad_layouts() {
+        let errors = [
+            ("T1F", "unsupported component count 1 for 'T'"),
+            ("P2F", "unsupported component count 2 for 'P'"),
+            ("W5B", "unsupported component count 5 for 'W'"),
+            ("X3F", "unknown buffer type 'X'"),
+            ("PF", "missing component count"),
+            ("P3D", "unknown buffer format \"D\""),
+            ("P3F__N3F", "empty layout element"),
+        ];
+        for (layout, message) in errors {
+            let error = format!("{:#}", get_buffers_from_layout(layout).unwrap_err());
+            assert!(error.contains(message), "{}: {}", layout, error);
+        }
+    }
+
+    #[test]
+    fn mirrored_tangent_frames() {
+        let frame = |binormal: [f32; 4], w: f32| {
+            let mut vertex = Vertex {
+                normal: [0.0, 0.0, 2.0, 0.0],
+                tangent: [3.0, 0.0, 0.0, w],
+                binormal,
+                ..Default::default()
+            };
+            vertex.normalize_tangent_frame();
+            vertex.tangent
+        };
+        // cross(normal, tangent) is +y, a binormal pointing the other way mirrors the UVs
+        assert_eq!(frame([0.0, 0.5, 0.0, 0.0], 0.0), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(frame([0.0, -0.5, 0.0, 0.0], 0.0), [1.0, 0.0, 0.0, -1.0]);
+        // Without a binormal stream the packed w decides
+        assert_eq!(frame([0.0; 4], 1.0), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(frame([0.0; 4], -1.0), [1.0, 0.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn decode_bytes() {
+        use DirectionEncoding::*;
+        for (buffer_type, value, directions, expected) in [
+            (BufferType::Normal, 0x7f, Snorm, 1.0),
+            (BufferType::Normal, 0x81, Snorm, -1.0),
+            (BufferType::Normal, 0x80, Snorm, -1.0),
+            (BufferType::Tangent, 0x00, Snorm, 0.0),
+            (BufferType::Binormal, 0xff, BiasedUnorm, 1.0),
+            (BufferType::Normal, 0x00, BiasedUnorm, -1.0),
+            // Only directions are signed
+            (BufferType::Color, 0xff, Snorm, 1.0),
+            (BufferType::Texcoord, 0x00, Snorm, 0.0),
+            (BufferType::BlendWeight, 0x33, BiasedUnorm, 0.2),
+            (BufferType::BlendIndices, 0xc8, Snorm, 200.0),
+            (BufferType::Position, 0x05, Snorm, 5.0),
+        ] {
+            let decoded = buffer_type.decode_byte(value, directions);
+            assert!(
+                (decoded - expected).abs() < 1e-6,
+                "{:?} {:#x} {:?} = {}",
+                buffer_type,
+                value,
+                directions,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn detect_direction_encoding() {
+        let buffers = get_buffers_from_layout("P3F_N4B").unwrap();
+        let stream = |normals: &[[u8; 4]]| {
+            let mut data = Vec::new();
+            for normal in normals {
+                data.extend_from_slice(&[0; 12]);
+                data.extend_from_slice(normal);
+            }
+            data
+        };
+        // +x, -y, +z and a diagonal, written both ways
+        let snorm = stream(&[
+            [127, 0, 0, 0],
+            [0, 0x81, 0, 0],
+            [0, 0, 127, 0],
+            [90, 0, 0xa6, 0],
+        ]);
+        let biased = stream(&[
+            [255, 128, 128, 0],
+            [128, 0, 128, 0],
+            [128, 128, 255, 0],
+            [218, 128, 38, 0],
+        ]);
+        assert_eq!(
+            DirectionEncoding::detect(&buffers, &snorm),
+            DirectionEncoding::Snorm
+        );
+        assert_eq!(
+            DirectionEncoding::detect(&buffers, &biased),
+            DirectionEncoding::BiasedUnorm
+        );
+        // Nothing to go by
+        let buffers = get_buffers_from_layout("P3F_C4B").unwrap();
+        assert_eq!(
+            DirectionEncoding::detect(&buffers, &biased),
+            DirectionEncoding::Snorm
+        );
+    }
+
+    #[test]
+    fn read_half_components() {
+        let buffer = get_buffers_from_layout("T4H").unwrap()[0];
+        let mut data = Vec::new();
+        for bits in [0x3c00u16, 0xc000, 0x3800, 0x7bff] {
+            data.extend_from_slice(&bits.to_le_bytes());
+        }
+        let mut reader = std::io::Cursor::new(data);
+        let values = (0..4)
+            .map(|_| {
+                buffer
+                    .read_component(&mut reader, DirectionEncoding::Snorm)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, [1.0, -2.0, 0.5, 65504.0]);
+    }
+}
@@ -1,4 +1,5 @@
 use binrw::{binread, BinRead};
+use serde::Serialize;
 use serde_json::Value;
 use std::{
     collections::HashMap,
@@ -11,7 +12,7 @@ use std::{
 use crate::{compression, version::Version, Args};
 
 #[binread]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct MpkInfo {
     #[br(temp)]
     pub path_size: u32,
@@ -65,7 +66,7 @@ pub struct ResourcesMpkInfo {
     pub records: Vec<ResourcesMpkRecord>,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 pub struct ResourcesMpkRecord {
     pub asset_size: u32,
     pub flags: u32,
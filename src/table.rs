@@ -0,0 +1,397 @@
+use anyhow::{bail, Error, Result};
+use rayon::prelude::*;
+use rmpv::Value;
+use rusqlite::types::Value as Cell;
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    etsb::{self, Etsb},
+    file,
+    version::Version,
+};
+
+#[derive(clap::ValueEnum, PartialEq, Debug, Clone, Copy, Default)]
+pub enum TableFormat {
+    /// One .csv per file, mirrored into the output directory
+    #[default]
+    #[value(name = "csv")]
+    Csv,
+    /// Every file as a table of one database
+    #[value(name = "sqlite")]
+    Sqlite,
+}
+
+/// An ETSB file flattened into rows, one column per msgpack map key.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+/// Plain JSON for nested values, unlike `.ejson` this doesn't need to convert back.
+fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => json!(b),
+        Value::Integer(n) => match n.as_u64() {
+            Some(n) => json!(n),
+            None => json!(n.as_i64()),
+        },
+        Value::F32(f) => json!(f),
+        Value::F64(f) => json!(f),
+        Value::String(s) => json!(String::from_utf8_lossy(s.as_bytes())),
+        Value::Binary(bytes) => json!(etsb::to_hex(bytes)),
+        Value::Array(values) => serde_json::Value::Array(values.iter().map(to_json).collect()),
+        Value::Map(entries) => serde_json::Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| (key_name(key), to_json(value)))
+                .collect(),
+        ),
+        Value::Ext(ext_type, data) => json!([ext_type, etsb::to_hex(data)]),
+    }
+}
+
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(s) => String::from_utf8_lossy(s.as_bytes()).to_string(),
+        key => key.to_string(),
+    }
+}
+
+fn to_cell(value: &Value) -> Cell {
+    match value {
+        Value::Nil => Cell::Null,
+        Value::Boolean(b) => Cell::Integer(*b as i64),
+        // Only u64 above i64::MAX doesn't fit
+        Value::Integer(n) => n
+            .as_i64()
+            .map_or_else(|| Cell::Text(n.to_string()), Cell::Integer),
+        Value::F32(f) => Cell::Real(*f as f64),
+        Value::F64(f) => Cell::Real(*f),
+        Value::String(s) => Cell::Text(String::from_utf8_lossy(s.as_bytes()).to_string()),
+        Value::Binary(bytes) => Cell::Blob(bytes.clone()),
+        value => Cell::Text(to_json(value).to_string()),
+    }
+}
+
+fn record_fields(record: &Value) -> Vec<(String, &Value)> {
+    match record {
+        Value::Map(entries) => entries
+            .iter()
+            .map(|(key, value)| (key_name(key), value))
+            .collect(),
+        value => vec![("value".to_string(), value)],
+    }
+}
+
+impl Table {
+    /// Game tables are a map of id to record or a list of records, anything else becomes
+    /// a single row.
+    pub fn from_value(name: &str, body: &Value) -> Self {
+        let records: Vec<(Option<&Value>, &Value)> = match body {
+            Value::Map(entries)
+                if !entries.is_empty() && entries.iter().all(|(_, value)| value.is_map()) =>
+            {
+                entries
+                    .iter()
+                    .map(|(key, value)| (Some(key), value))
+                    .collect()
+            }
+            Value::Array(values)
+                if !values.is_empty() && values.iter().all(|value| value.is_map()) =>
+            {
+                values.iter().map(|value| (None, value)).collect()
+            }
+            body => vec![(None, body)],
+        };
+        let mut columns = Vec::new();
+        let mut column_index = HashMap::new();
+        let mut taken = HashSet::new();
+        let mut add_column = |name: String, columns: &mut Vec<String>| {
+            *column_index.entry(name.clone()).or_insert_with(|| {
+                // SQLite column names ignore case, `Name` and `name` need telling apart
+                let mut unique = name.clone();
+                let mut n = 1;
+                while !taken.insert(unique.to_lowercase()) {
+                    n += 1;
+                    unique = format!("{}_{}", name, n);
+                }
+                columns.push(unique);
+                columns.len() - 1
+            })
+        };
+        let keyed = records.iter().any(|(key, _)| key.is_some());
+        let key_column = if keyed {
+            let fields = records
+                .iter()
+                .flat_map(|(_, record)| record_fields(record))
+                .map(|(name, _)| name.to_lowercase())
+                .collect::<HashSet<_>>();
+            let mut name = "key".to_string();
+            while fields.contains(&name) {
+                name.insert(0, '_');
+            }
+            Some(add_column(name, &mut columns))
+        } else {
+            None
+        };
+
+        let mut cells = Vec::new();
+        for (key, record) in &records {
+            let mut row = Vec::new();
+            if let (Some(column), Some(key)) = (key_column, key) {
+                row.push((column, to_cell(key)));
+            }
+            for (name, value) in record_fields(record) {
+                row.push((add_column(name, &mut columns), to_cell(value)));
+            }
+            cells.push(row);
+        }
+        let rows = cells
+            .into_iter()
+            .map(|cells| {
+                let mut row = vec![Cell::Null; columns.len()];
+                for (column, cell) in cells {
+                    row[column] = cell;
+                }
+                row
+            })
+            .collect();
+
+        Self {
+            name: name.to_string(),
+            columns,
+            rows,
+        }
+    }
+
+    /// SQLite type every value of a column fits, blank when they're mixed.
+    fn column_type(&self, column: usize) -> &'static str {
+        let mut column_type = "";
+        for row in &self.rows {
+            column_type = match (&row[column], column_type) {
+                (Cell::Null, _) => column_type,
+                (Cell::Integer(_), "" | "INTEGER") => "INTEGER",
+                (Cell::Integer(_) | Cell::Real(_), "" | "INTEGER" | "REAL") => "REAL",
+                (Cell::Text(_), "" | "TEXT") => "TEXT",
+                (Cell::Blob(_), "" | "BLOB") => "BLOB",
+                _ => return "",
+            };
+        }
+        column_type
+    }
+
+    pub fn write_csv(&self, output_path: &Path) -> Result<()> {
+        let field = |cell: &Cell| {
+            let text = match cell {
+                Cell::Null => String::new(),
+                Cell::Integer(n) => n.to_string(),
+                Cell::Real(f) => f.to_string(),
+                Cell::Text(s) => s.clone(),
+                Cell::Blob(bytes) => etsb::to_hex(bytes),
+            };
+            if text.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", text.replace('"', "\"\""))
+            } else {
+                text
+            }
+        };
+
+        let mut csv = String::new();
+        let header = self
+            .columns
+            .iter()
+            .map(|name| field(&Cell::Text(name.clone())));
+        csv.push_str(&header.collect::<Vec<_>>().join(","));
+        csv.push_str("\r\n");
+        for row in &self.rows {
+            csv.push_str(&row.iter().map(field).collect::<Vec<_>>().join(","));
+            csv.push_str("\r\n");
+        }
+        let mut output_file = File::create(output_path)?;
+        output_file.write_all(csv.as_bytes())?;
+        Ok(())
+    }
+
+    /// Replaces the table of the same name in `connection`.
+    pub fn write_sqlite(&self, connection: &rusqlite::Connection) -> Result<()> {
+        if self.columns.is_empty() {
+            bail!("no columns to make a table from");
+        }
+        let quote = |name: &str| format!("\"{}\"", name.replace('"', "\"\""));
+        let name = quote(&self.name);
+        let columns = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| format!("{} {}", quote(column), self.column_type(i)))
+            .collect::<Vec<_>>();
+        connection.execute_batch(&format!(
+            "DROP TABLE IF EXISTS {name}; CREATE TABLE {name} ({});",
+            columns.join(", ")
+        ))?;
+
+        let placeholders = vec!["?"; self.columns.len()].join(", ");
+        let mut insert =
+            connection.prepare(&format!("INSERT INTO {name} VALUES ({placeholders})"))?;
+        for row in &self.rows {
+            insert.execute(rusqlite::params_from_iter(row))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn load_table(etsb_path: &Path, name: &str, version: &Version) -> Result<Table> {
+    let data = std::fs::read(etsb_path)?;
+    let etsb = Etsb::decode(&data, version)?;
+    Ok(Table::from_value(name, &etsb.body))
+}
+
+/// Flattens an ETSB file, or every one under a directory, into CSV files mirrored into
+/// `output_path` or tables of the SQLite database at `output_path`.
+pub fn export_tables(
+    input_path: &Path,
+    output_path: &Path,
+    format: TableFormat,
+    version: &Version,
+) -> Result<()> {
+    let (root, files) = if input_path.is_dir() {
+        let files = file::walk_files(input_path)?
+            .into_par_iter()
+            .filter(|path| etsb::is_etsb(path))
+            .collect::<Vec<_>>();
+        (input_path, files)
+    } else {
+        let root = input_path.parent().unwrap_or(Path::new(""));
+        (root, vec![input_path.to_path_buf()])
+    };
+    println!("Found {} etsb files", files.len());
+
+    // Tables are named after their path, `weapons/rifles.etsb` is `weapons_rifles`
+    let mut names = HashSet::new();
+    let files = files
+        .into_iter()
+        .map(|path| {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            let name = relative
+                .with_extension("")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("_");
+            let mut unique = name.clone();
+            let mut n = 1;
+            while !names.insert(unique.to_lowercase()) {
+                n += 1;
+                unique = format!("{}_{}", name, n);
+            }
+            (path, relative, unique)
+        })
+        .collect::<Vec<(PathBuf, PathBuf, String)>>();
+
+    let results = match format {
+        TableFormat::Csv => files
+            .par_iter()
+            .map(|(path, relative, name)| {
+                let output_path = file::add_extension(&output_path.join(relative), "csv");
+                let result =
+                    std::fs::create_dir_all(output_path.parent().unwrap_or(output_path.as_path()))
+                        .map_err(Error::from)
+                        .and_then(|_| load_table(path, name, version))
+                        .and_then(|table| table.write_csv(&output_path));
+                (path, result)
+            })
+            .collect::<Vec<_>>(),
+        TableFormat::Sqlite => {
+            let tables = files
+                .par_iter()
+                .map(|(path, _, name)| (path, load_table(path, name, version)))
+                .collect::<Vec<_>>();
+            let mut connection = rusqlite::Connection::open(output_path)?;
+            let transaction = connection.transaction()?;
+            let results = tables
+                .into_iter()
+                .map(|(path, table)| {
+                    (
+                        path,
+                        table.and_then(|table| table.write_sqlite(&transaction)),
+                    )
+                })
+                .collect::<Vec<_>>();
+            transaction.commit()?;
+            results
+        }
+    };
+
+    let failed = results
+        .iter()
+        .filter_map(|(path, result)| result.as_ref().err().map(|e| (path, e)))
+        .inspect(|(path, e)| println!("Failed {:?}: {:#}", path, e))
+        .count();
+    println!("{} tables, {} failed", results.len(), failed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(fields: &[(&str, i64)]) -> Value {
+        Value::Map(
+            fields
+                .iter()
+                .map(|(name, value)| (Value::from(*name), Value::from(*value)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn columns_differ_ignoring_case() {
+        let body = Value::Map(vec![
+            (
+                Value::from(1),
+                record(&[("key", 1), ("Key", 2), ("_KEY", 3), ("Name", 4)]),
+            ),
+            (Value::from(2), record(&[("name", 5), ("Name_2", 6)])),
+        ]);
+        let table = Table::from_value("t", &body);
+        assert_eq!(
+            table.columns,
+            ["__key", "key", "Key_2", "_KEY", "Name", "name_2", "Name_2_2"]
+        );
+        assert_eq!(
+            table.rows[1],
+            [
+                Cell::Integer(2),
+                Cell::Null,
+                Cell::Null,
+                Cell::Null,
+                Cell::Null,
+                Cell::Integer(5),
+                Cell::Integer(6),
+            ]
+        );
+        let names = table
+            .columns
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect::<HashSet<_>>();
+        assert_eq!(names.len(), table.columns.len());
+    }
+
+    #[test]
+    fn list_of_records_has_no_key() {
+        let body = Value::Array(vec![record(&[("a", 1)]), record(&[("b", 2)])]);
+        let table = Table::from_value("t", &body);
+        assert_eq!(table.columns, ["a", "b"]);
+        assert_eq!(table.rows[1], [Cell::Null, Cell::Integer(2)]);
+    }
+}
@@ -1,14 +1,17 @@
 #![allow(non_camel_case_types)]
 use std::{
-    io::{Read, Seek},
-    path::Path,
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
 use binrw::{BinRead, BinReaderExt};
+use rayon::prelude::*;
+use serde::Serialize;
 
-use crate::{compression, version::Version};
+use crate::{compression, inspect::Region, version::Version};
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 #[br(repr = u8)]
 enum SamplerFilter {
     FNone = 0,
@@ -17,7 +20,7 @@ enum SamplerFilter {
     Anisotropic = 3,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 #[br(repr = u8)]
 enum SampleAddress {
     ANone = 0,
@@ -27,7 +30,7 @@ enum SampleAddress {
     FromTexture = 4,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 #[br(repr = u8)]
 enum PixelFormat {
     R32G32B32A32 = 3,
@@ -99,7 +102,7 @@ enum PixelFormat {
     R32G32B32A32UI = 69,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 #[br(repr = u8)]
 enum TextureType {
     Texture1D = 0,
@@ -111,7 +114,7 @@ enum TextureType {
     Array = 6,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 #[br(repr = u8)]
 enum TextureCompressionPresets {
     Default = 0,
@@ -138,7 +141,7 @@ enum TextureCompressionPresets {
     TerrainNormalMap = 21,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 #[br(repr = u8)]
 enum ETextureLODGroup {
     World = 0,
@@ -164,7 +167,7 @@ enum ETextureLODGroup {
     ImageBaseReflection = 20,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 #[br(repr = u8)]
 enum ETextureMipGen {
     FromTextureGroup = 0,
@@ -175,19 +178,29 @@ enum ETextureMipGen {
     AlphaDistribution = 5,
 }
 
-// #[bitfield]
-// #[derive(BinRead, Debug, Clone)]
-// #[br(map = Self::from_bytes)]
-// pub struct Flags {
-//     is_srgb: bool,
-//     is_dynamic_range: bool,
-//     none_compression: bool,
-//     compression_no_alpha: bool,
-//     none_mip_downgrading: bool,
-//     _reserved: B3,
-// }
+#[derive(BinRead, Debug, Clone, Copy, Serialize)]
+#[br(map = |b: u8| Self::from(b))]
+pub struct TextureFlags {
+    is_srgb: bool,
+    is_dynamic_range: bool,
+    none_compression: bool,
+    compression_no_alpha: bool,
+    none_mip_downgrading: bool,
+}
 
-#[derive(BinRead, Debug, Clone)]
+impl From<u8> for TextureFlags {
+    fn from(b: u8) -> Self {
+        Self {
+            is_srgb: b & 0x1 != 0,
+            is_dynamic_range: b & 0x2 != 0,
+            none_compression: b & 0x4 != 0,
+            compression_no_alpha: b & 0x8 != 0,
+            none_mip_downgrading: b & 0x10 != 0,
+        }
+    }
+}
+
+#[derive(BinRead, Debug, Clone, Serialize)]
 pub struct TexHeader {
     mag_filter: SamplerFilter,
     min_filter: SamplerFilter,
@@ -196,7 +209,7 @@ pub struct TexHeader {
     address_v: SampleAddress,
     fmt: PixelFormat,
     miplevel: u8,
-    flags: u8,
+    flags: TextureFlags,
     compression_preset: TextureCompressionPresets,
     lod_group: ETextureLODGroup,
     mip_gen_preset: ETextureMipGen,
@@ -209,7 +222,7 @@ pub struct TexHeader {
     slice_count: u16,
 }
 
-#[derive(BinRead, Debug, Clone)]
+#[derive(BinRead, Debug, Clone, Serialize)]
 pub struct TextureSliceInfo {
     size: u32,
     width: u16,
@@ -219,202 +232,860 @@ pub struct TextureSliceInfo {
     slice_in_byte: u32,
 }
 
-pub fn export_texture(version: &Version, texture_path: &str) -> anyhow::Result<(), anyhow::Error> {
+impl TextureSliceInfo {
+    /// Size of the slice info itself, which is included in `size`.
+    const SIZE: u32 = 0x10;
+}
+
+/// Reads and decompresses the payload that follows a slice info.
+fn read_slice_data<R: Read>(
+    version: &Version,
+    reader: &mut R,
+    slice_info: &TextureSliceInfo,
+) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        slice_info.size >= TextureSliceInfo::SIZE,
+        "Slice size {:#x} is smaller than the slice info",
+        slice_info.size
+    );
+    let mut payload = vec![0; (slice_info.size - TextureSliceInfo::SIZE) as usize];
+    reader.read_exact(&mut payload)?;
+
+    if payload.len() < 0x8 {
+        return Ok(payload);
+    }
+    match compression::get_compression_type(&payload) {
+        Some(compression_type) => compression::decompress(version, compression_type, &payload),
+        None => Ok(payload),
+    }
+}
+
+impl TextureType {
+    /// Number of 2D faces stored for each array layer.
+    fn face_count(&self) -> u16 {
+        match self {
+            TextureType::Cube | TextureType::CubeArray => 6,
+            _ => 1,
+        }
+    }
+}
+
+/// Channels holding X and Y of a two channel normal map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NormalPacking {
+    /// X in red, Y in green, as BC5 stores them
+    RedGreen,
+    /// X in red/green/blue, Y in alpha, as astcenc's `-normal` mode (and A8L8) packs them
+    LuminanceAlpha,
+    /// X in alpha, Y in green, the DXT5nm packing of BC3
+    AlphaGreen,
+}
+
+impl NormalPacking {
+    /// Indices of the X and Y channels in an RGBA pixel.
+    fn channels(&self) -> (usize, usize) {
+        match self {
+            NormalPacking::RedGreen => (0, 1),
+            NormalPacking::LuminanceAlpha => (0, 3),
+            NormalPacking::AlphaGreen => (3, 1),
+        }
+    }
+}
+
+const CUBE_FACE_NAMES: [&str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+
+// Face positions (column, row) in a horizontal 4x3 cross
+const CUBE_CROSS_POSITIONS: [(u32, u32); 6] = [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (3, 1)];
+
+/// Where a slice sits in the texture, derived from its index in the slice sequence.
+/// Slices are assumed to be stored element by element (layer/face), each with its full
+/// mip chain, which is the D3D subresource order with faces in the +X, -X, +Y, -Y, +Z, -Z
+/// order of D3D cube maps. The order isn't confirmed by a multi-slice sample.
+#[derive(Debug, Clone, Copy)]
+struct SliceLocation {
+    layer: u16,
+    face: u16,
+    mip: u16,
+}
+
+/// Slices per element, 1 when the slice count isn't a whole number of mip chains and every
+/// slice is treated as its own element.
+fn mip_chain_len(header: &TexHeader) -> u16 {
+    let mip_count = (header.miplevel as u16).max(1);
+    if header.slice_count / mip_count * mip_count == header.slice_count {
+        mip_count
+    } else {
+        1
+    }
+}
+
+fn locate_slice(header: &TexHeader, index: u16) -> SliceLocation {
+    let mip_count = mip_chain_len(header);
+    let face_count = header.texture_type.face_count();
+    let element = index / mip_count;
+    SliceLocation {
+        layer: element / face_count,
+        face: element % face_count,
+        mip: index % mip_count,
+    }
+}
+
+fn slice_output_path(
+    output_path: &Path,
+    header: &TexHeader,
+    location: SliceLocation,
+    depth: Option<u16>,
+) -> PathBuf {
+    let mut suffix = String::new();
+    match header.texture_type {
+        TextureType::Cube => {
+            suffix.push_str(&format!("{}.", CUBE_FACE_NAMES[location.face as usize]));
+        }
+        TextureType::CubeArray => {
+            suffix.push_str(&format!(
+                "layer{}.{}.",
+                location.layer, CUBE_FACE_NAMES[location.face as usize]
+            ));
+        }
+        TextureType::Texture2DArray | TextureType::Array => {
+            suffix.push_str(&format!("layer{}.", location.layer));
+        }
+        _ => {
+            if header.slice_count / mip_chain_len(header) > 1 {
+                suffix.push_str(&format!("{}.", location.layer));
+            }
+        }
+    }
+    if let Some(depth) = depth {
+        suffix.push_str(&format!("depth{}.", depth));
+    }
+    if location.mip > 0 {
+        suffix.push_str(&format!("mip{}.", location.mip));
+    }
+    output_path.with_extension(format!("{}png", suffix))
+}
+
+fn decode_pixels(
+    fmt: &PixelFormat,
+    data: &[u8],
+    width: usize,
+    height: usize,
+) -> anyhow::Result<Vec<u32>> {
+    let mut image: Vec<u32> = vec![0; width * height];
+    match fmt {
+        PixelFormat::ASTC_10x10_LDR | PixelFormat::ASTC_10x10_HDR => {
+            texture2ddecoder::decode_astc_10_10(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_10x5_LDR | PixelFormat::ASTC_10x5_HDR => {
+            texture2ddecoder::decode_astc_10_5(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_10x6_LDR | PixelFormat::ASTC_10x6_HDR => {
+            texture2ddecoder::decode_astc_10_6(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_10x8_LDR | PixelFormat::ASTC_10x8_HDR => {
+            texture2ddecoder::decode_astc_10_8(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_12x10_LDR | PixelFormat::ASTC_12x10_HDR => {
+            texture2ddecoder::decode_astc_12_10(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_12x12_LDR | PixelFormat::ASTC_12x12_HDR => {
+            texture2ddecoder::decode_astc_12_12(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_4x4_LDR | PixelFormat::ASTC_4x4_HDR => {
+            texture2ddecoder::decode_astc_4_4(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_5x4_LDR | PixelFormat::ASTC_5x4_HDR => {
+            texture2ddecoder::decode_astc_5_4(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_5x5_LDR | PixelFormat::ASTC_5x5_HDR => {
+            texture2ddecoder::decode_astc_5_5(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_6x5_LDR | PixelFormat::ASTC_6x5_HDR => {
+            texture2ddecoder::decode_astc_6_5(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_6x6_LDR | PixelFormat::ASTC_6x6_HDR => {
+            texture2ddecoder::decode_astc_6_6(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_8x5_LDR | PixelFormat::ASTC_8x5_HDR => {
+            texture2ddecoder::decode_astc_8_5(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_8x6_LDR | PixelFormat::ASTC_8x6_HDR => {
+            texture2ddecoder::decode_astc_8_6(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        PixelFormat::ASTC_8x8_LDR | PixelFormat::ASTC_8x8_HDR => {
+            texture2ddecoder::decode_astc_8_8(data, width, height, &mut image)
+                .map_err(anyhow::Error::msg)?;
+        }
+        _ => {
+            anyhow::bail!("Unsupported format {:?}", fmt);
+        }
+    }
+
+    Ok(image)
+}
+
+fn to_rgba_image(pixels: &[u32], width: u32, height: u32) -> image::RgbaImage {
+    let mut img = image::RgbaImage::new(width, height);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let color = pixels[(y * width + x) as usize];
+        *pixel = image::Rgba([
+            ((color >> 16) & 0xFF) as u8,
+            ((color >> 8) & 0xFF) as u8,
+            (color & 0xFF) as u8,
+            ((color >> 24) & 0xFF) as u8,
+        ]);
+    }
+    img
+}
+
+/// Lays out the six faces of a cube mip as a horizontal cross.
+fn build_cube_cross(faces: &[&image::RgbaImage]) -> image::RgbaImage {
+    let size = faces[0].width();
+    let mut cross = image::RgbaImage::new(size * 4, size * 3);
+    for (face, (col, row)) in faces.iter().zip(CUBE_CROSS_POSITIONS) {
+        image::imageops::replace(&mut cross, *face, (col * size) as i64, (row * size) as i64);
+    }
+    cross
+}
+
+/// Which way the green channel of exported normal maps points.
+/// Source textures are assumed to be authored in the DirectX (Y-) convention.
+#[derive(clap::ValueEnum, PartialEq, Debug, Clone, Copy)]
+pub enum NormalConvention {
+    #[value(name = "directx")]
+    DirectX,
+    #[value(name = "opengl")]
+    OpenGL,
+}
+
+impl TexHeader {
+    fn is_two_channel_normal_map(&self) -> bool {
+        matches!(
+            self.compression_preset,
+            TextureCompressionPresets::NormalMap
+                | TextureCompressionPresets::NormalMapBC5
+                | TextureCompressionPresets::NormalMapCompact
+                | TextureCompressionPresets::TerrainNormalMap
+        )
+    }
+
+    /// Where the format keeps X and Y of a two channel normal map. The BC5 preset names
+    /// its format outright, otherwise the block format decides.
+    fn normal_packing(&self) -> NormalPacking {
+        use PixelFormat::*;
+        if matches!(
+            self.compression_preset,
+            TextureCompressionPresets::NormalMapBC5
+        ) {
+            return NormalPacking::RedGreen;
+        }
+        match self.fmt {
+            BC2 | BC3 => NormalPacking::AlphaGreen,
+            A8L8 | ASTC_4x4_LDR | ASTC_5x4_LDR | ASTC_5x5_LDR | ASTC_6x5_LDR | ASTC_6x6_LDR
+            | ASTC_8x5_LDR | ASTC_8x6_LDR | ASTC_8x8_LDR | ASTC_10x5_LDR | ASTC_10x6_LDR
+            | ASTC_10x8_LDR | ASTC_10x10_LDR | ASTC_12x10_LDR | ASTC_12x12_LDR => {
+                NormalPacking::LuminanceAlpha
+            }
+            _ => NormalPacking::RedGreen,
+        }
+    }
+
+    /// Normal maps hold vectors, so they are linear even if flagged as sRGB.
+    fn is_srgb(&self) -> bool {
+        self.flags.is_srgb && !self.is_two_channel_normal_map()
+    }
+}
+
+/// Rebuilds the Z channel of a two channel normal map, optionally flipping Y.
+fn reconstruct_normal_map(
+    img: &mut image::RgbaImage,
+    packing: NormalPacking,
+    convention: NormalConvention,
+) {
+    let (x_channel, y_channel) = packing.channels();
+    for pixel in img.pixels_mut() {
+        let x = pixel[x_channel] as f32 / 255.0 * 2.0 - 1.0;
+        let mut y = pixel[y_channel] as f32 / 255.0 * 2.0 - 1.0;
+        if convention == NormalConvention::OpenGL {
+            y = -y;
+        }
+        let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+        *pixel = image::Rgba([
+            ((x * 0.5 + 0.5) * 255.0).round() as u8,
+            ((y * 0.5 + 0.5) * 255.0).round() as u8,
+            ((z * 0.5 + 0.5) * 255.0).round() as u8,
+            255,
+        ]);
+    }
+}
+
+/// Writes a PNG tagged as either sRGB or linear (gamma 1.0).
+fn save_png(img: &image::RgbaImage, path: PathBuf, srgb: bool) -> anyhow::Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, img.width(), img.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    if srgb {
+        encoder.set_srgb(png::SrgbRenderingIntent::Perceptual);
+    } else {
+        encoder.set_source_gamma(png::ScaledFloat::new(1.0));
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(img.as_raw())?;
+    Ok(())
+}
+
+/// Decodes a slice surface and applies the conversions its header asks for.
+fn convert_surface(
+    header: &TexHeader,
+    data: &[u8],
+    width: usize,
+    height: usize,
+    normal_convention: NormalConvention,
+) -> anyhow::Result<image::RgbaImage> {
+    let pixels = decode_pixels(&header.fmt, data, width, height)?;
+    let mut img = to_rgba_image(&pixels, width as u32, height as u32);
+    if header.is_two_channel_normal_map() {
+        reconstruct_normal_map(&mut img, header.normal_packing(), normal_convention);
+    }
+    Ok(img)
+}
+
+/// Converts one texture file, writing images and the metadata sidecar next to `output_path`.
+pub fn convert_texture(
+    version: &Version,
+    texture_path: &Path,
+    output_path: &Path,
+    normal_convention: NormalConvention,
+) -> anyhow::Result<TexHeader> {
     let mut file = std::fs::File::open(texture_path)?;
     let header: TexHeader = file.read_le()?;
-    println!("{:#?}", header);
+
+    // Decoded faces of cube textures, keyed by (layer, mip), for building crosses
+    let mut cube_faces: HashMap<(u16, u16), Vec<Option<image::RgbaImage>>> = HashMap::new();
 
     for i in 0..header.slice_count {
+        let slice_start = file.stream_position()?;
         let slice_info: TextureSliceInfo = file.read_le()?;
-        println!("{:#?}", slice_info);
+
+        let data = read_slice_data(version, &mut file, &slice_info)?;
+        file.seek(SeekFrom::Start(slice_start + slice_info.size as u64))?;
 
         if slice_info.slice_in_byte == 0 {
             println!("Empty slice");
             continue;
         }
 
-        let mut data = Vec::new();
-        let comp_type: u32 = file.read_le()?;
-        file.seek(std::io::SeekFrom::Current(-4))?;
+        let location = locate_slice(&header, i);
+        let width = slice_info.width as usize;
+        let height = slice_info.height as usize;
 
-        match compression::get_compression_type(&comp_type.to_le_bytes()).unwrap() {
-            compression::CompressionType::None => {
-                if slice_info.slice_in_byte > 0 {
-                    data.resize(slice_info.slice_in_byte as usize, 0);
-                } else {
-                    data.resize(
-                        slice_info.width as usize * slice_info.height as usize * 4,
-                        0,
-                    );
+        if matches!(header.texture_type, TextureType::Texture3D) && slice_info.depth > 1 {
+            // Volume slices hold every depth layer back to back
+            for d in 0..slice_info.depth {
+                let start = d as usize * slice_info.slice_in_byte as usize;
+                let end = (start + slice_info.slice_in_byte as usize).min(data.len());
+                if start >= end {
+                    break;
                 }
-            }
-            compression::CompressionType::LZ4 => {
-                let mut compressed_data = vec![0; slice_info.size as usize - 16];
-                file.read_exact(&mut compressed_data)?;
-                data = compression::decompress(
-                    version,
-                    compression::CompressionType::LZ4,
-                    &compressed_data,
-                )?;
-            }
-            compression::CompressionType::Zstd => {
-                let compressed_data = vec![0; slice_info.slice_in_byte as usize];
-                data = compression::decompress(
-                    version,
-                    compression::CompressionType::Zstd,
-                    &compressed_data,
+                let img =
+                    convert_surface(&header, &data[start..end], width, height, normal_convention)?;
+                save_png(
+                    &img,
+                    slice_output_path(output_path, &header, location, Some(d)),
+                    header.is_srgb(),
                 )?;
             }
-            _ => {}
+            continue;
         }
 
-        let mut image: Vec<u32> = vec![0; slice_info.width as usize * slice_info.height as usize];
-        match header.fmt {
-            PixelFormat::ASTC_10x10_LDR | PixelFormat::ASTC_10x10_HDR => {
-                texture2ddecoder::decode_astc_10_10(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_10x5_LDR | PixelFormat::ASTC_10x5_HDR => {
-                texture2ddecoder::decode_astc_10_5(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_10x6_LDR | PixelFormat::ASTC_10x6_HDR => {
-                texture2ddecoder::decode_astc_10_6(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_10x8_LDR | PixelFormat::ASTC_10x8_HDR => {
-                texture2ddecoder::decode_astc_10_8(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_12x10_LDR | PixelFormat::ASTC_12x10_HDR => {
-                texture2ddecoder::decode_astc_12_10(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_12x12_LDR | PixelFormat::ASTC_12x12_HDR => {
-                texture2ddecoder::decode_astc_12_12(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_4x4_LDR | PixelFormat::ASTC_4x4_HDR => {
-                texture2ddecoder::decode_astc_4_4(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_5x4_LDR | PixelFormat::ASTC_5x4_HDR => {
-                texture2ddecoder::decode_astc_5_4(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_5x5_LDR | PixelFormat::ASTC_5x5_HDR => {
-                texture2ddecoder::decode_astc_5_5(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_6x5_LDR | PixelFormat::ASTC_6x5_HDR => {
-                texture2ddecoder::decode_astc_6_5(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_6x6_LDR | PixelFormat::ASTC_6x6_HDR => {
-                texture2ddecoder::decode_astc_6_6(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_8x5_LDR | PixelFormat::ASTC_8x5_HDR => {
-                texture2ddecoder::decode_astc_8_5(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_8x6_LDR | PixelFormat::ASTC_8x6_HDR => {
-                texture2ddecoder::decode_astc_8_6(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            PixelFormat::ASTC_8x8_LDR | PixelFormat::ASTC_8x8_HDR => {
-                texture2ddecoder::decode_astc_8_8(
-                    &data,
-                    slice_info.width as usize,
-                    slice_info.height as usize,
-                    &mut image,
-                )
-                .unwrap();
-            }
-            _ => {
-                println!("Unsupported format {:?}", header.fmt);
-            }
+        let img = convert_surface(&header, &data, width, height, normal_convention)?;
+        save_png(
+            &img,
+            slice_output_path(output_path, &header, location, None),
+            header.is_srgb(),
+        )?;
+
+        if header.texture_type.face_count() == 6 {
+            let faces = cube_faces
+                .entry((location.layer, location.mip))
+                .or_insert_with(|| vec![None; 6]);
+            faces[location.face as usize] = Some(img);
+        }
+    }
+
+    let mut cube_faces = cube_faces.into_iter().collect::<Vec<_>>();
+    cube_faces.sort_by_key(|(key, _)| *key);
+    for ((layer, mip), faces) in cube_faces {
+        let Some(faces) = faces.iter().map(Option::as_ref).collect::<Option<Vec<_>>>() else {
+            println!(
+                "Cube layer {} mip {} is missing faces, skipping cross",
+                layer, mip
+            );
+            continue;
+        };
+        let mut suffix = String::new();
+        if matches!(header.texture_type, TextureType::CubeArray) {
+            suffix.push_str(&format!("layer{}.", layer));
+        }
+        suffix.push_str("cross.");
+        if mip > 0 {
+            suffix.push_str(&format!("mip{}.", mip));
+        }
+        save_png(
+            &build_cube_cross(&faces),
+            output_path.with_extension(format!("{}png", suffix)),
+            header.is_srgb(),
+        )?;
+    }
+
+    // Written once every slice decoded, named apart from the `.json` of material exports
+    let mut sidecar = std::fs::File::create(output_path.with_extension("tex.json"))?;
+    sidecar.write_all(serde_json::to_string_pretty(&header)?.as_bytes())?;
+
+    Ok(header)
+}
+
+pub fn read_header(texture_path: &Path) -> anyhow::Result<TexHeader> {
+    let mut file = std::fs::File::open(texture_path)?;
+    Ok(file.read_le()?)
+}
+
+/// The image standing in for the whole texture once converted to `output_path`, the
+/// cross of the first cube or else the first slice. `None` until it has been written.
+pub fn preview_image(output_path: &Path, header: &TexHeader) -> Option<PathBuf> {
+    let first = SliceLocation {
+        layer: 0,
+        face: 0,
+        mip: 0,
+    };
+    let mut candidates = match header.texture_type {
+        TextureType::Cube => vec![output_path.with_extension("cross.png")],
+        TextureType::CubeArray => vec![output_path.with_extension("layer0.cross.png")],
+        _ => vec![],
+    };
+    // Cubes missing faces only have the faces, volumes with depth are split per layer
+    candidates.push(slice_output_path(output_path, header, first, None));
+    candidates.push(slice_output_path(output_path, header, first, Some(0)));
+    candidates.into_iter().find(|path| path.exists())
+}
+
+pub fn export_texture(
+    version: &Version,
+    texture_path: &str,
+    normal_convention: NormalConvention,
+) -> anyhow::Result<(), anyhow::Error> {
+    let texture_path = Path::new(texture_path);
+    let header = convert_texture(version, texture_path, texture_path, normal_convention)?;
+    println!("{:#?}", header);
+    Ok(())
+}
+
+/// Checks whether a file starts with a plausible texture header and first slice.
+pub fn probe_texture(path: &Path) -> Option<TexHeader> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let file_size = file.metadata().ok()?.len();
+    read_texture_header(&mut file, file_size)
+}
+
+fn read_texture_header<R: Read + Seek>(reader: &mut R, file_size: u64) -> Option<TexHeader> {
+    let header: TexHeader = reader.read_le().ok()?;
+    if header.width == 0 || header.height == 0 || header.slice_count == 0 {
+        return None;
+    }
+    let slice_start = reader.stream_position().ok()?;
+    let slice_info: TextureSliceInfo = reader.read_le().ok()?;
+    reader.seek(SeekFrom::Start(slice_start)).ok()?;
+    if slice_info.size < TextureSliceInfo::SIZE
+        || slice_start + slice_info.size as u64 > file_size
+        || slice_info.width > header.width
+        || slice_info.height > header.height
+    {
+        return None;
+    }
+    Some(header)
+}
+
+/// Header and slice layout of a texture, `None` if it doesn't look like one.
+pub fn texture_regions<R: Read + Seek>(reader: &mut R, file_size: u64) -> Option<Vec<Region>> {
+    let header = read_texture_header(reader, file_size)?;
+    let mut regions = vec![Region::parsed(
+        0,
+        reader.stream_position().ok()?,
+        "TexHeader",
+        &header,
+    )];
+    for i in 0..header.slice_count {
+        let slice_start = reader.stream_position().ok()?;
+        let Ok(slice_info) = reader.read_le::<TextureSliceInfo>() else {
+            break;
+        };
+        let slice_end = slice_start + slice_info.size as u64;
+        if slice_info.size < TextureSliceInfo::SIZE || slice_end > file_size {
+            break;
         }
+        let data_start = slice_start + TextureSliceInfo::SIZE as u64;
+        regions.push(Region::parsed(
+            slice_start,
+            data_start,
+            &format!("TextureSliceInfo {}", i),
+            &slice_info,
+        ));
+        let mut magic = [0; 4];
+        let compression = if slice_end - data_start >= 4 {
+            reader.read_exact(&mut magic).ok()?;
+            compression::get_compression_type(&magic)
+        } else {
+            None
+        };
+        regions.push(Region::parsed(
+            data_start,
+            slice_end,
+            &format!("slice {} data", i),
+            &serde_json::json!({ "compression": compression.map(|c| format!("{:?}", c)) }),
+        ));
+        reader.seek(SeekFrom::Start(slice_end)).ok()?;
+    }
+    Some(regions)
+}
+
+/// Converts every texture found under `input_path`, mirroring the tree into `output_root`.
+pub fn export_texture_tree(
+    version: &Version,
+    input_path: &Path,
+    output_root: &Path,
+    normal_convention: NormalConvention,
+) -> anyhow::Result<()> {
+    let files = crate::file::walk_files(input_path)?;
+    println!("Scanning {} files", files.len());
 
-        let mut img = image::ImageBuffer::new(slice_info.width as u32, slice_info.height as u32);
-        for (x, y, pixel) in img.enumerate_pixels_mut() {
-            let color = image[(y * slice_info.width as u32 + x) as usize];
-            *pixel = image::Rgba([
-                ((color >> 16) & 0xFF) as u8,
-                ((color >> 8) & 0xFF) as u8,
-                (color & 0xFF) as u8,
-                ((color >> 24) & 0xFF) as u8,
-            ]);
+    let results = files
+        .par_iter()
+        .filter_map(|path| probe_texture(path).map(|header| (path, header)))
+        .map(|(path, header)| {
+            let output_path = output_root.join(path.strip_prefix(input_path).unwrap_or(path));
+            let result = output_path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| convert_texture(version, path, &output_path, normal_convention));
+            (path, format!("{:?}", header.fmt), result)
+        })
+        .collect::<Vec<_>>();
+
+    let mut format_counts: HashMap<String, (usize, usize)> = HashMap::new();
+    for (path, fmt, result) in &results {
+        let counts = format_counts.entry(fmt.clone()).or_default();
+        match result {
+            Ok(_) => counts.0 += 1,
+            Err(e) => {
+                counts.1 += 1;
+                println!("Failed {:?} ({}): {}", path, fmt, e);
+            }
         }
+    }
 
-        let output_path = Path::new(texture_path).with_extension(format!("{}.png", i));
-        img.save(output_path)?;
+    let mut format_counts = format_counts.into_iter().collect::<Vec<_>>();
+    format_counts.sort();
+    for (fmt, (converted, failed)) in format_counts {
+        println!("{:<16} converted: {:<6} failed: {}", fmt, converted, failed);
     }
+    let failed = results.iter().filter(|(_, _, r)| r.is_err()).count();
+    println!("{} textures, {} failed", results.len(), failed);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compression::CompressionType;
+    use std::io::Cursor;
+
+    /// Bytes of a texture payload, repetitive enough for every codec to shrink it.
+    fn pixels() -> Vec<u8> {
+        (0..64u8).cycle().take(300).collect()
+    }
+
+    fn container(magic: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut payload = magic.to_vec();
+        payload.extend_from_slice(&(pixels().len() as u32).to_le_bytes());
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    /// Reads `payload` as a slice followed by the next slice's bytes, checking the reader
+    /// stops at `size - 0x10`.
+    fn read_payload(payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let slice_info = TextureSliceInfo {
+            size: payload.len() as u32 + TextureSliceInfo::SIZE,
+            width: 0,
+            height: 0,
+            depth: 0,
+            pitch_in_byte: 0,
+            slice_in_byte: 0,
+        };
+        let mut data = payload.to_vec();
+        data.extend_from_slice(b"NEXT");
+        let mut reader = Cursor::new(data);
+        let decompressed = read_slice_data(&Version::ClosedBeta, &mut reader, &slice_info)?;
+        assert_eq!(reader.position(), payload.len() as u64);
+        Ok(decompressed)
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(read_payload(&pixels()).unwrap(), pixels());
+    }
+
+    #[test]
+    fn shorter_than_a_compression_header() {
+        assert_eq!(read_payload(b"NNNN").unwrap(), b"NNNN");
+    }
+
+    #[test]
+    fn stored_strips_header() {
+        let payload = container(b"NNNN", &pixels());
+        assert_eq!(read_payload(&payload).unwrap(), pixels());
+    }
+
+    #[test]
+    fn lz4() {
+        let payload = container(b"ZZZ4", &lz4_flex::compress(&pixels()));
+        assert_eq!(read_payload(&payload).unwrap(), pixels());
+    }
+
+    #[test]
+    fn zstd() {
+        let payload = container(b"ZSTD", &zstd::encode_all(&pixels()[..], 0).unwrap());
+        assert_eq!(read_payload(&payload).unwrap(), pixels());
+    }
+
+    #[test]
+    fn lzma() {
+        // Properties and dictionary size only, the unpacked size is in the container header
+        let options = lzma_rs::compress::Options {
+            unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+        };
+        let mut compressed = Vec::new();
+        lzma_rs::lzma_compress_with_options(&mut &pixels()[..], &mut compressed, &options).unwrap();
+        let payload = container(b"LZMA", &compressed);
+        assert_eq!(read_payload(&payload).unwrap(), pixels());
+    }
+
+    #[test]
+    fn zlib() {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&pixels()).unwrap();
+        let mut payload = encoder.finish().unwrap();
+        // 8 trailing bytes and the start XORed, the zlib header becomes the e2 06 magic
+        payload.extend_from_slice(&[0; 8]);
+        let end = (128 - (payload.len() - 8) % 37).min(payload.len());
+        for x in &mut payload[..end] {
+            *x ^= 154;
+        }
+        assert_eq!(payload[..2], [0xe2, 0x06]);
+        assert_eq!(read_payload(&payload).unwrap(), pixels());
+    }
+
+    #[test]
+    fn g108() {
+        for compression_type in [CompressionType::G108Lz4, CompressionType::G108Zstd] {
+            let payload =
+                compression::compress(&Version::ClosedBeta, compression_type, &pixels()).unwrap();
+            assert_eq!(read_payload(&payload).unwrap(), pixels());
+        }
+    }
+
+    #[test]
+    fn offset() {
+        let mut payload = b"CCCC".to_vec();
+        payload.extend(container(b"ZZZ4", &lz4_flex::compress(&pixels())));
+        payload.extend_from_slice(&[0; 20]);
+        assert_eq!(read_payload(&payload).unwrap(), pixels());
+    }
+
+    #[test]
+    fn size_smaller_than_slice_info() {
+        let slice_info = TextureSliceInfo {
+            size: TextureSliceInfo::SIZE - 1,
+            width: 0,
+            height: 0,
+            depth: 0,
+            pitch_in_byte: 0,
+            slice_in_byte: 0,
+        };
+        let mut reader = Cursor::new(pixels());
+        assert!(read_slice_data(&Version::ClosedBeta, &mut reader, &slice_info).is_err());
+    }
+
+    /// A 4x4 R8G8B8A8 texture with one slice of `slice_size` bytes behind the header.
+    fn texture_file(miplevel: u8, slice_size: u32) -> Vec<u8> {
+        let mut data = vec![0, 0, 0, 0, 0, 5, miplevel, 0, 0, 0, 0, 1];
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&slice_size.to_le_bytes());
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&16u16.to_le_bytes());
+        data.extend_from_slice(&64u32.to_le_bytes());
+        data.resize(
+            data.len() + slice_size as usize - TextureSliceInfo::SIZE as usize,
+            0,
+        );
+        data
+    }
+
+    #[test]
+    fn probe_headers() {
+        for miplevel in [0, 1, 3] {
+            let data = texture_file(miplevel, 0x50);
+            let header = read_texture_header(&mut Cursor::new(&data), data.len() as u64);
+            assert!(header.is_some(), "miplevel {}", miplevel);
+        }
+        // A slice running past the end of the file
+        let data = texture_file(1, 0x50);
+        let header = read_texture_header(&mut Cursor::new(&data), data.len() as u64 - 1);
+        assert!(header.is_none());
+    }
+
+    fn header(texture_type: TextureType, miplevel: u8, slice_count: u16) -> TexHeader {
+        TexHeader {
+            mag_filter: SamplerFilter::Linear,
+            min_filter: SamplerFilter::Linear,
+            mip_filter: SamplerFilter::Linear,
+            address_u: SampleAddress::Wrap,
+            address_v: SampleAddress::Wrap,
+            fmt: PixelFormat::R8G8B8A8,
+            miplevel,
+            flags: TextureFlags::from(0),
+            compression_preset: TextureCompressionPresets::Default,
+            lod_group: ETextureLODGroup::World,
+            mip_gen_preset: ETextureMipGen::FromTextureGroup,
+            texture_type,
+            width: 4,
+            height: 4,
+            default_color: [0.0; 4],
+            size: 0,
+            unk: 0,
+            slice_count,
+        }
+    }
+
+    /// Location and file name of every slice of `header`.
+    fn slices(header: &TexHeader) -> Vec<((u16, u16, u16), String)> {
+        (0..header.slice_count)
+            .map(|i| {
+                let location = locate_slice(header, i);
+                let path = slice_output_path(Path::new("out/rock.tex"), header, location, None);
+                (
+                    (location.layer, location.face, location.mip),
+                    path.file_name().unwrap().to_string_lossy().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mip_slices() {
+        assert_eq!(
+            slices(&header(TextureType::Texture2D, 3, 3)),
+            [
+                ((0, 0, 0), "rock.png".to_string()),
+                ((0, 0, 1), "rock.mip1.png".to_string()),
+                ((0, 0, 2), "rock.mip2.png".to_string()),
+            ]
+        );
+        // miplevel 0 is a single level
+        assert_eq!(
+            slices(&header(TextureType::Texture2D, 0, 1)),
+            [((0, 0, 0), "rock.png".to_string())]
+        );
+    }
+
+    #[test]
+    fn cube_slices() {
+        let slices = slices(&header(TextureType::Cube, 2, 12));
+        assert_eq!(slices[0], ((0, 0, 0), "rock.px.png".to_string()));
+        assert_eq!(slices[1], ((0, 0, 1), "rock.px.mip1.png".to_string()));
+        assert_eq!(slices[2], ((0, 1, 0), "rock.nx.png".to_string()));
+        assert_eq!(slices[11], ((0, 5, 1), "rock.nz.mip1.png".to_string()));
+
+        let slices = self::slices(&header(TextureType::CubeArray, 1, 12));
+        assert_eq!(slices[5], ((0, 5, 0), "rock.layer0.nz.png".to_string()));
+        assert_eq!(slices[7], ((1, 1, 0), "rock.layer1.nx.png".to_string()));
+    }
+
+    #[test]
+    fn array_slices() {
+        let slices = slices(&header(TextureType::Texture2DArray, 2, 6));
+        assert_eq!(slices[0], ((0, 0, 0), "rock.layer0.png".to_string()));
+        assert_eq!(slices[3], ((1, 0, 1), "rock.layer1.mip1.png".to_string()));
+        assert_eq!(slices[4], ((2, 0, 0), "rock.layer2.png".to_string()));
+    }
+
+    #[test]
+    fn partial_mip_chains() {
+        // 4 slices can't be whole chains of 3 mips, each one is its own layer
+        let slices = slices(&header(TextureType::Texture2D, 3, 4));
+        assert_eq!(slices[0], ((0, 0, 0), "rock.0.png".to_string()));
+        assert_eq!(slices[3], ((3, 0, 0), "rock.3.png".to_string()));
+    }
+
+    #[test]
+    fn normal_packings() {
+        // X = 0.6, Y = -0.8 packed the way each format stores it, the rest is noise
+        let (x, y) = (204, 25);
+        for (packing, pixel) in [
+            (NormalPacking::RedGreen, [x, y, 7, 99]),
+            (NormalPacking::LuminanceAlpha, [x, x, x, y]),
+            (NormalPacking::AlphaGreen, [255, y, 7, x]),
+        ] {
+            let mut img = image::RgbaImage::from_pixel(1, 1, image::Rgba(pixel));
+            reconstruct_normal_map(&mut img, packing, NormalConvention::DirectX);
+            assert_eq!(img.get_pixel(0, 0).0, [x, y, 128, 255], "{:?}", packing);
+
+            let mut img = image::RgbaImage::from_pixel(1, 1, image::Rgba(pixel));
+            reconstruct_normal_map(&mut img, packing, NormalConvention::OpenGL);
+            assert_eq!(img.get_pixel(0, 0).0, [x, 230, 128, 255], "{:?}", packing);
+        }
+    }
+
+    #[test]
+    fn normal_packing_from_format() {
+        let mut normal_map = header(TextureType::Texture2D, 1, 1);
+        normal_map.compression_preset = TextureCompressionPresets::NormalMap;
+        for (fmt, packing) in [
+            (PixelFormat::ASTC_4x4_LDR, NormalPacking::LuminanceAlpha),
+            (PixelFormat::ASTC_8x8_LDR, NormalPacking::LuminanceAlpha),
+            (PixelFormat::BC3, NormalPacking::AlphaGreen),
+            (PixelFormat::BC5, NormalPacking::RedGreen),
+            (PixelFormat::R8G8B8A8, NormalPacking::RedGreen),
+        ] {
+            normal_map.fmt = fmt;
+            assert_eq!(normal_map.normal_packing(), packing, "{:?}", normal_map.fmt);
+        }
+        normal_map.compression_preset = TextureCompressionPresets::NormalMapBC5;
+        normal_map.fmt = PixelFormat::ASTC_4x4_LDR;
+        assert_eq!(normal_map.normal_packing(), NormalPacking::RedGreen);
+    }
+}